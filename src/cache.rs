@@ -0,0 +1,373 @@
+use std::fs;
+use std::io::{BufWriter,Write};
+use std::path::{Path,PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+use memmap2::Mmap;
+use super::logic::{Buff,Setting};
+use super::statespace::StateSpace;
+use super::table::{Table,ActionTable,calc_table,extend_table};
+
+// 表のキャッシュです。計算した表を設定ごとにファイルに保存して、次からは計算せずにファイルをマップして参照します。
+// 同じファイルをマップした複数のプロセスは、ページキャッシュ上の1つのコピーを共有します。
+// 保存形式や表の計算、ルールの内容を変えた時はCACHE_VERSIONを上げて古いキャッシュを使わないようにしてください
+const CACHE_VERSION : u32 = 14;
+const MAGIC : &[u8;8] = b"CFTABLE\0";
+
+// キャッシュのディレクトリに置く表の合計の上限です。超えたら最後に使った時刻の古いものから消します
+const MAX_CACHE_SIZE : u64 = 16 << 30;
+
+// キャッシュのキーです。設定(データファイルのルールは定義の中身も)とバージョンを全て文字列にしたものです。
+// ファイルの中にはキーそのものを保存して読み込み時に照合します。
+// 品質に上限が無ければ開始品質は表に影響しないので、キーから除きます
+fn cache_key( setting:&Setting ) -> String {
+    format!("craft-finisher {} cache {} {:?}", env!("CARGO_PKG_VERSION"), CACHE_VERSION, setting.table_setting())
+}
+
+// 最大CPだけが違う設定の表は広げて使えるので、ファイル名は最大CPを除いたキーのハッシュと最大CPにします
+fn family_prefix( setting:&Setting ) -> String {
+    format!("{:016x}-", fnv1a(cache_key(&Setting { max_cp:0, ..setting.clone() }).as_bytes()))
+}
+
+// FNV-1a(64bit)です。標準ライブラリのハッシュはバージョン間で同じ値になる保証がないので自前で計算します
+const FNV_OFFSET : u64 = 0xcbf29ce484222325;
+
+fn fnv1a( bytes:&[u8] ) -> u64 {
+    fnv1a_continue(FNV_OFFSET, bytes)
+}
+
+// 途中までのハッシュに続けて計算します。順に書き出す中身のチェックサムに使います
+fn fnv1a_continue( h:u64, bytes:&[u8] ) -> u64 {
+    bytes.iter().fold(h, |h,b| (h ^ *b as u64).wrapping_mul(0x100000001b3))
+}
+
+// XDG_CACHE_HOMEか、無ければ~/.cacheの下に置きます
+fn cache_dir() -> Option<PathBuf> {
+    let base = match std::env::var_os("XDG_CACHE_HOME") {
+        Some(x) if PathBuf::from(&x).is_absolute() => PathBuf::from(x),
+        _ => PathBuf::from(std::env::var_os("HOME")?).join(".cache"),
+    };
+    Some(base.join("craft-finisher"))
+}
+
+fn cache_path( setting:&Setting ) -> Option<PathBuf> {
+    Some(cache_dir()?.join(format!("{}{}.table", family_prefix(setting), setting.max_cp)))
+}
+
+// 最大CPが低い表のうち、最も高いものの最大CPです
+fn lower_max_cp( setting:&Setting ) -> Option<u16> {
+    let prefix = family_prefix(setting);
+    fs::read_dir(cache_dir()?).ok()?.filter_map(|e| {
+        let name = e.ok()?.file_name().into_string().ok()?;
+        name.strip_prefix(&prefix)?.strip_suffix(".table")?.parse::<u16>().ok()
+    }).filter(|cp| *cp < setting.max_cp).max()
+}
+
+// 形式は、ヘッダ(マジック、バージョン、キー、バフの一覧、状態数、ヘッダのチェックサム)、評価値、アクション、中身のチェックサムの順です。
+// 評価値はリトルエンディアンのu32で8バイト境界から始まるので、マップしたままu32の列として参照できます。
+// 中身のチェックサムを確かめるために読み込み時に全てのページを1度読みますが、計算し直すよりずっと速く済みます。
+// 表と同じ大きさのバッファを作らないように、ヘッダの後は順に書き出します
+fn encode( w:&mut impl Write, key:&str, values:&Table<u32>, actions:&ActionTable ) -> std::io::Result<()> {
+    let space = values.space();
+    let size = space.size();
+    let mut header = Vec::with_capacity(key.len() + space.buffs.len() * Buff::NUM_FIELDS + 64);
+
+    header.extend_from_slice(MAGIC);
+    header.extend_from_slice(&CACHE_VERSION.to_le_bytes());
+    header.extend_from_slice(&(key.len() as u32).to_le_bytes());
+    header.extend_from_slice(key.as_bytes());
+    header.extend_from_slice(&(space.buffs.len() as u32).to_le_bytes());
+    for b in &space.buffs {
+        header.extend_from_slice(&b.fields());
+    }
+    header.extend_from_slice(&(size as u64).to_le_bytes());
+    let checksum = fnv1a(&header);
+    header.extend_from_slice(&checksum.to_le_bytes());
+    header.resize(header.len().next_multiple_of(8), 0);
+    w.write_all(&header)?;
+
+    let mut body = FNV_OFFSET;
+    for i in 0..size {
+        let bytes = values.at(i).to_le_bytes();
+        body = fnv1a_continue(body, &bytes);
+        w.write_all(&bytes)?;
+    }
+    for i in 0..size {
+        let bytes = [actions.code_at(i)];
+        body = fnv1a_continue(body, &bytes);
+        w.write_all(&bytes)?;
+    }
+    w.write_all(&body.to_le_bytes())?;
+    w.flush()
+}
+
+// 先頭から順に読みます。長さが足りない場合はNoneです
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n:usize) -> Option<&'a [u8]> {
+        let x = self.bytes.get(self.pos..self.pos.checked_add(n)?)?;
+        self.pos += n;
+        Some(x)
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_le_bytes(self.take(4)?.try_into().ok()?))
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        Some(u64::from_le_bytes(self.take(8)?.try_into().ok()?))
+    }
+}
+
+// マップしたキャッシュのファイルです。評価値の表とアクションの表で同じマップを共有します
+#[derive(Debug,Clone)]
+pub struct MappedValues {
+    map: Arc<Mmap>,
+    values_offset: usize,
+    actions_offset: usize,
+    size: usize,
+}
+
+impl MappedValues {
+    pub fn values(&self) -> &[u32] {
+        // 評価値の位置は8バイト境界で、マップの先頭はページ境界なので、前後に余りは出ません
+        let bytes = &self.map[self.values_offset..self.values_offset + self.size * 4];
+        let (head,values,tail) = unsafe { bytes.align_to::<u32>() };
+        assert!(head.is_empty() && tail.is_empty());
+        values
+    }
+
+    // アクションは表と同じ番号で保存します。キーにルールが入っているので並びは変わりません
+    pub fn action_code(&self, index:usize) -> &u8 {
+        assert!(index < self.size);
+        &self.map[self.actions_offset + index]
+    }
+}
+
+// 壊れたファイルや別の設定のファイルはNoneになります。ビッグエンディアンの環境ではマップしたまま参照できないので使いません
+fn decode( setting:&Setting, key:&str, map:Mmap ) -> Option<(Table<u32>,ActionTable)> {
+    if cfg!(target_endian = "big") {
+        return None;
+    }
+
+    let mut r = Reader { bytes:&map, pos:0 };
+    if r.take(MAGIC.len())? != MAGIC || r.u32()? != CACHE_VERSION {
+        return None;
+    }
+
+    let key_len = r.u32()? as usize;
+    if r.take(key_len)? != key.as_bytes() {
+        return None;
+    }
+
+    let num_buffs = r.u32()? as usize;
+    let mut buffs = Vec::with_capacity(num_buffs);
+    for _ in 0..num_buffs {
+        let f = r.take(Buff::NUM_FIELDS)?;
+        let mut b = Buff::default();
+        for (i,x) in f.iter().enumerate() {
+            *b.field_mut(i) = *x;
+        }
+        buffs.push(b);
+    }
+
+    let size = r.u64()? as usize;
+    let header_len = r.pos;
+    if fnv1a(&map[..header_len]) != r.u64()? {
+        return None;
+    }
+
+    let space = StateSpace::with_buffs(setting, buffs);
+    let values_offset = r.pos.next_multiple_of(8);
+    let actions_offset = values_offset.checked_add(size.checked_mul(4)?)?;
+    let end = actions_offset.checked_add(size)?;
+    if size != space.size() || map.len() != end.checked_add(8)? {
+        return None;
+    }
+    if fnv1a(&map[values_offset..end]) != u64::from_le_bytes(map[end..].try_into().ok()?) {
+        return None;
+    }
+
+    let mapped = MappedValues { map:Arc::new(map), values_offset, actions_offset, size };
+    Some((Table::mapped(mapped.clone(), space.clone()), ActionTable::new(setting, Table::mapped(mapped, space))))
+}
+
+// ファイルをマップします。キャッシュは置き換える時に別のファイルに書いてから名前を変えるので、
+// マップしている間に中身が書き換わることはありません
+fn map( path:&Path ) -> Option<Mmap> {
+    let file = fs::File::open(path).ok()?;
+    unsafe { Mmap::map(&file) }.ok()
+}
+
+// 一時ファイルに書いてから置き換えるので、書き込み途中のファイルを読むことはありません。
+// 書き込みに失敗した場合は一時ファイルを消します
+fn save( path:&Path, key:&str, values:&Table<u32>, actions:&ActionTable ) -> std::io::Result<()> {
+    fs::create_dir_all(path.parent().unwrap())?;
+    let tmp = path.with_extension(format!("tmp{}", std::process::id()));
+    let result = fs::File::create(&tmp).and_then(|f| encode(&mut BufWriter::new(f), key, values, actions)).and_then(|_| fs::rename(&tmp, path));
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    result
+}
+
+// 表の合計が上限を超えていれば、最後に使った時刻の古い表から消します。保存したばかりの表は残します
+fn evict( dir:&Path, keep:&Path, limit:u64 ) {
+    let Ok(entries) = fs::read_dir(dir) else { return };
+
+    let mut tables : Vec<(SystemTime,u64,PathBuf)> = entries.filter_map(|e| {
+        let e = e.ok()?;
+        let path = e.path();
+        let meta = e.metadata().ok()?;
+        (path.extension()? == "table" && meta.is_file()).then_some((meta.modified().ok()?, meta.len(), path))
+    }).collect();
+    tables.sort();
+
+    let mut total : u64 = tables.iter().map(|(_,len,_)| len).sum();
+    for (_,len,path) in tables {
+        if total <= limit {
+            break;
+        }
+        if path != keep && fs::remove_file(&path).is_ok() {
+            total -= len;
+        }
+    }
+}
+
+// 読み込んだ表は更新時刻を今にして、消す順番を後にします
+fn load( setting:&Setting ) -> Option<(Table<u32>,ActionTable)> {
+    let path = cache_path(setting)?;
+    let tables = decode(setting, &cache_key(setting), map(&path)?)?;
+    let _ = fs::File::options().write(true).open(&path).and_then(|f| f.set_modified(SystemTime::now()));
+    Some(tables)
+}
+
+// キャッシュの場所が分からない場合や保存できない場合も、計算した表はそのまま使います
+fn store( setting:&Setting, tables:(Table<u32>,ActionTable) ) -> (Table<u32>,ActionTable) {
+    if let Some(p) = cache_path(setting) {
+        match save(&p, &cache_key(setting), &tables.0, &tables.1) {
+            Ok(()) => evict(p.parent().unwrap(), &p, MAX_CACHE_SIZE),
+            Err(e) => eprintln!("cannot save table cache {}: {}", p.display(), e),
+        }
+    }
+    tables
+}
+
+// キャッシュがあれば読み込み、無ければ最大CPの低い表のキャッシュを広げるか、それも無ければ計算して保存します
+pub fn load_or_calc_table( setting:&Setting ) -> Result<(Table<u32>,ActionTable),String> {
+    if let Some(tables) = load(setting) {
+        return Ok(tables);
+    }
+
+    let lower = lower_max_cp(setting).and_then(|cp| load(&Setting { max_cp:cp, ..setting.clone() }));
+    Ok(match lower {
+        Some((values,actions)) => store(setting, extend_table(setting, &values, &actions)?),
+        None => store(setting, calc_table(setting)?),
+    })
+}
+
+// 最大CPを上げる時に、キャッシュが無ければ今の表を広げて保存します
+pub fn load_or_extend_table( setting:&Setting, values:&Table<u32>, actions:&ActionTable ) -> Result<(Table<u32>,ActionTable),String> {
+    match load(setting) {
+        Some(tables) => Ok(tables),
+        None => Ok(store(setting, extend_table(setting, values, actions)?)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rules::Rules;
+
+    // テストごとに別の一時ディレクトリを使います
+    fn temp_dir( name:&str ) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("craft-finisher-test-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    // 保存した表は同じキーでだけ読み込め、計算した表と同じ評価値とアクションになります
+    #[test]
+    fn save_and_load_round_trip() {
+        let dir = temp_dir("round-trip");
+        let setting = Setting::example( Rules::Patch5, 20, 20 );
+        let (tv,ta) = calc_table( &setting ).unwrap();
+        let path = dir.join("a.table");
+        save( &path, &cache_key(&setting), &tv, &ta ).unwrap();
+        assert!( !path.with_extension(format!("tmp{}", std::process::id())).exists() );
+
+        let (lv,la) = decode( &setting, &cache_key(&setting), map(&path).unwrap() ).unwrap();
+        for i in 0..tv.space().size() {
+            assert_eq!( (lv.at(i), la.code_at(i)), (tv.at(i), ta.code_at(i)) );
+        }
+
+        let other = Setting { reserve_cp:1, ..setting.clone() };
+        assert!( decode( &setting, &cache_key(&other), map(&path).unwrap() ).is_none() );
+
+        // 表は品質の上限に関わる設定を使わないので、それらはキーに入りません
+        let capped = Setting { max_quality:1000, initial_quality:500, objective:crate::logic::Objective::HqPercent, ..setting.clone() };
+        assert_eq!( cache_key(&capped), cache_key(&setting) );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // 合計が上限を超えた分だけ、更新時刻の古い表から消します。残すと指定した表は古くても消しません
+    #[test]
+    fn evicts_least_recently_used() {
+        let dir = temp_dir("evict");
+        let now = SystemTime::now();
+        let paths : Vec<PathBuf> = (0..4).map(|i| dir.join(format!("{}.table", i))).collect();
+        for (i,p) in paths.iter().enumerate() {
+            fs::write(p, [0u8;100]).unwrap();
+            let age = std::time::Duration::from_secs(100 * (4 - i as u64));
+            fs::File::options().write(true).open(p).unwrap().set_modified(now - age).unwrap();
+        }
+        fs::write(dir.join("other.txt"), [0u8;1000]).unwrap();
+
+        evict( &dir, &paths[0], 250 );
+        let exists : Vec<bool> = paths.iter().map(|p| p.exists()).collect();
+        assert_eq!( exists, [true, false, false, true] );
+        assert!( dir.join("other.txt").exists() );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // 読み込んだ表はマップしたファイルの中を直接参照します。長さが合わないファイルや中身が壊れたファイルは使いません
+    #[test]
+    fn decodes_in_place_and_rejects_broken_files() {
+        let dir = temp_dir("mapped");
+        let setting = Setting::example( Rules::Patch5, 10, 20 );
+        let key = cache_key(&setting);
+        let (tv,ta) = calc_table( &setting ).unwrap();
+        let path = dir.join("a.table");
+        save( &path, &key, &tv, &ta ).unwrap();
+
+        let map = map(&path).unwrap();
+        let range = map.as_ptr_range();
+        let (lv,la) = decode( &setting, &key, map ).unwrap();
+        let last = tv.space().size() - 1;
+        assert!( range.contains( &(lv.at(0) as *const u32 as *const u8) ) );
+        assert!( range.contains( &(lv.at(last) as *const u32 as *const u8) ) );
+        assert_eq!( la.code_at(last), ta.code_at(last) );
+
+        let bytes = fs::read(&path).unwrap();
+        let broken = dir.join("broken.table");
+        fs::write( &broken, &bytes[..bytes.len() - 1] ).unwrap();
+        assert!( decode( &setting, &key, super::map(&broken).unwrap() ).is_none() );
+
+        // キーの後のバフの一覧や、評価値とアクションが壊れている場合はチェックサムで分かります
+        for pos in [MAGIC.len() + 8 + key.len() + 4, bytes.len() - 8 - tv.space().size() * 5, bytes.len() - 9] {
+            let mut corrupt = bytes.clone();
+            corrupt[pos] ^= 1;
+            fs::write( &broken, &corrupt ).unwrap();
+            assert!( decode( &setting, &key, super::map(&broken).unwrap() ).is_none() );
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
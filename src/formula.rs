@@ -0,0 +1,148 @@
+
+use core::cmp::min;
+
+// 品質と工数の計算式です
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Hash)]
+pub enum QualityFormula {
+    Approximate,            // 記事の近似式
+    Exact(RecipeLevel),     // レシピレベルの表を使った計算式(6.0以降のルールのみ)
+}
+
+// レシピレベルごとの係数です
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Hash)]
+pub struct RecipeLevel {
+    pub recipe_level: u16,          // レシピレベル
+    pub class_level: u8,            // レシピのクラスレベル
+    pub progress_divider: u32,      // 作業精度の除数
+    pub quality_divider: u32,       // 加工精度の除数
+    pub progress_modifier: u32,     // 工数の補正(%)
+    pub quality_modifier: u32,      // 品質の補正(%)
+}
+
+// RecipeLevelTableから必要な分だけ抜き出しています。載っているのは6.xの560、570、580、590、610、620、640と、
+// 7.xの690、700、710、720だけで、それ以外のレシピレベル(5.x以前のレシピや、6.xと7.xの低いレベルのレシピなど)は載っていません。
+// 載っていないレシピレベルはRecipeLevelを直接作って指定してください。REPLでは載っているレベルの一覧を示して断ります
+const RECIPE_LEVEL_TABLE : [RecipeLevel;11] = [
+    RecipeLevel { recipe_level: 560, class_level: 90, progress_divider: 130, quality_divider: 115, progress_modifier: 90, quality_modifier: 80 },
+    RecipeLevel { recipe_level: 570, class_level: 90, progress_divider: 130, quality_divider: 115, progress_modifier: 90, quality_modifier: 80 },
+    RecipeLevel { recipe_level: 580, class_level: 90, progress_divider: 130, quality_divider: 115, progress_modifier: 90, quality_modifier: 80 },
+    RecipeLevel { recipe_level: 590, class_level: 90, progress_divider: 130, quality_divider: 115, progress_modifier: 90, quality_modifier: 80 },
+    RecipeLevel { recipe_level: 610, class_level: 90, progress_divider: 130, quality_divider: 115, progress_modifier: 80, quality_modifier: 70 },
+    RecipeLevel { recipe_level: 620, class_level: 90, progress_divider: 130, quality_divider: 115, progress_modifier: 80, quality_modifier: 70 },
+    RecipeLevel { recipe_level: 640, class_level: 90, progress_divider: 130, quality_divider: 115, progress_modifier: 80, quality_modifier: 70 },
+    RecipeLevel { recipe_level: 690, class_level: 100, progress_divider: 170, quality_divider: 150, progress_modifier: 90, quality_modifier: 75 },
+    RecipeLevel { recipe_level: 700, class_level: 100, progress_divider: 170, quality_divider: 150, progress_modifier: 90, quality_modifier: 75 },
+    RecipeLevel { recipe_level: 710, class_level: 100, progress_divider: 170, quality_divider: 150, progress_modifier: 80, quality_modifier: 70 },
+    RecipeLevel { recipe_level: 720, class_level: 100, progress_divider: 170, quality_divider: 150, progress_modifier: 80, quality_modifier: 70 },
+];
+
+impl RecipeLevel {
+    pub fn find(recipe_level:u16) -> Option<RecipeLevel> {
+        RECIPE_LEVEL_TABLE.iter().find(|r| r.recipe_level == recipe_level).copied()
+    }
+
+    // クラフターのレベルがレシピのレベル以下の時だけ補正がかかります
+    fn modifier(&self, crafter_level:u8, modifier:u32) -> u32 {
+        if crafter_level <= self.class_level { modifier } else { 100 }
+    }
+
+    // 効率100%あたりの工数です
+    pub fn base_progress(&self, craftsmanship:u32, crafter_level:u8) -> u32 {
+        (craftsmanship * 10 / self.progress_divider + 2) * self.modifier(crafter_level, self.progress_modifier) / 100
+    }
+
+    // 効率100%あたりの品質です
+    pub fn base_quality(&self, process_accuracy:u32, crafter_level:u8) -> u32 {
+        (process_accuracy * 10 / self.quality_divider + 35) * self.modifier(crafter_level, self.quality_modifier) / 100
+    }
+}
+
+// 1回のアクションで上がる品質です。倍率は全て百分率の整数です。
+// ゲームと同じく、状態(コンディション)、効率、インナークワイエット、バフの順に倍率を掛けて、段階ごとに切り捨てます。
+// イノベーションとグレートストライドは足し合わせた1つの倍率で、他の倍率とは別の段階で切り捨てます
+pub fn quality_increase(base_quality:u32, condition_rate:u32, efficiency:u32, inner_quiet_rate:u32, buff_rate:u32) -> u32 {
+    [condition_rate, efficiency, inner_quiet_rate, buff_rate].iter().fold(base_quality as u64, |q,rate| q * *rate as u64 / 100) as u32
+}
+
+// 品質の割合(%)からHQ率(%)への表です
+const HQ_PERCENT_TABLE : [u64;101] = [
+    1, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5,
+    5, 6, 6, 6, 6, 7, 7, 7, 7, 8, 8, 8, 9, 9, 9, 10, 10, 10, 11, 11,
+    11, 12, 12, 12, 13, 13, 13, 14, 14, 14, 15, 15, 15, 16, 16, 17, 17, 17, 18, 18,
+    18, 19, 19, 20, 20, 21, 22, 23, 24, 26, 28, 31, 34, 38, 42, 47, 52, 58, 64, 68,
+    71, 74, 76, 78, 80, 81, 82, 83, 84, 85, 86, 87, 88, 89, 90, 91, 92, 94, 96, 98,
+    100,
+];
+
+// 品質からHQ率(%)を求めます
+pub fn hq_percent(quality:u32, max_quality:u32) -> u32 {
+    HQ_PERCENT_TABLE[min(quality as u64 * 100 / max_quality as u64, 100) as usize] as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // クラフターのレベルがレシピのクラスレベル以下の時だけ補正がかかり、クラスレベルを超えると補正はかかりません
+    #[test]
+    fn modifier_only_up_to_class_level() {
+        let r = RecipeLevel::find(560).unwrap();
+        assert_eq!( r.base_quality(3000, 90), r.base_quality(3000, 91) * r.quality_modifier / 100 );
+        assert_eq!( r.base_progress(3000, 90), r.base_progress(3000, 91) * r.progress_modifier / 100 );
+        assert_eq!( r.base_quality(3000, 91), r.base_quality(3000, 100) );
+    }
+
+    // 品質の計算式を、段階ごとに手で切り捨てた値で確かめます。コメントは効率100%あたりの品質と、各段階で倍率を掛けて切り捨てた値です。
+    // 倍率は状態、効率、インナークワイエット、バフ(イノベーションとグレートストライド)の順です
+    fn quality( recipe_level:u16, process_accuracy:u32, crafter_level:u8, rates:(u32,u32,u32,u32) ) -> u32 {
+        let r = RecipeLevel::find(recipe_level).unwrap();
+        let (condition_rate, efficiency, inner_quiet_rate, buff_rate) = rates;
+        quality_increase( r.base_quality(process_accuracy, crafter_level), condition_rate, efficiency, inner_quiet_rate, buff_rate )
+    }
+
+    // レシピのクラスレベル以下では補正がかかり、超えると補正はかかりません
+    #[test]
+    fn base_quality_and_progress() {
+        assert_eq!( RecipeLevel::find(560).unwrap().base_quality(3000, 90), 236 );
+        assert_eq!( RecipeLevel::find(640).unwrap().base_quality(4000, 100), 382 );
+        assert_eq!( RecipeLevel::find(690).unwrap().base_quality(4950, 100), 273 );
+        assert_eq!( RecipeLevel::find(720).unwrap().base_quality(5000, 100), 257 );
+        assert_eq!( RecipeLevel::find(720).unwrap().base_progress(5100, 100), 241 );
+    }
+
+    #[test]
+    fn patch6_recipes() {
+        assert_eq!( quality( 560, 3000, 90, (100,100,100,100) ), 236 );   // 加工: 236
+        assert_eq!( quality( 560, 3000, 90, (150,125,100,150) ), 663 );   // 高品質で中級加工、イノベーション: 236, 354, 442, 442, 663
+        assert_eq!( quality( 560, 3000, 90, (150,125,150,150) ), 994 );   // 同じくインナークワイエット5: 236, 354, 442, 663, 994(最後に1度だけ切り捨てると995)
+        assert_eq!( quality( 560, 3000, 90, (400,300,200,250) ), 14160 ); // 最高品質でビエルゴの祝福、全てのバフ: 236, 944, 2832, 5664, 14160
+        assert_eq!( quality( 640, 4000, 100, (50,100,170,150) ), 486 );   // 低品質で倹約加工、イノベーション: 382, 191, 191, 324, 486(同じく487)
+        assert_eq!( quality( 640, 4000, 100, (100,200,130,200) ), 1986 ); // 下地加工、グレートストライド: 382, 382, 764, 993, 1986
+    }
+
+    #[test]
+    fn patch7_recipes() {
+        assert_eq!( quality( 690, 4950, 100, (100,150,200,150) ), 1227 ); // 上級加工、インナークワイエット10、イノベーション: 273, 273, 409, 818, 1227(同じく1228)
+        assert_eq!( quality( 690, 4950, 100, (150,100,160,200) ), 1308 ); // 高品質で加工、グレートストライド: 273, 409, 409, 654, 1308
+        assert_eq!( quality( 720, 5000, 100, (400,200,140,150) ), 4317 ); // 最高品質で下地加工、イノベーション: 257, 1028, 2056, 2878, 4317
+        assert_eq!( quality( 720, 5000, 100, (50,300,200,250) ), 1920 );  // 低品質でビエルゴの祝福、全てのバフ: 257, 128, 384, 768, 1920
+        assert_eq!( quality( 720, 4500, 100, (100,125,200,250) ), 1460 ); // 中級加工、全てのバフ: 234, 234, 292, 584, 1460(同じく1462)
+    }
+
+    // HQ率は最高品質に対する割合(1%ごとに切り捨て)の段の表の値です。最高品質以上は100%です
+    #[test]
+    fn hq_percent_at_breakpoints() {
+        assert_eq!( hq_percent(0, 1000), 1 );
+        assert_eq!( hq_percent(690, 1000), 26 );
+        assert_eq!( hq_percent(700, 1000), 28 );
+        assert_eq!( hq_percent(790, 1000), 68 );
+        assert_eq!( hq_percent(990, 1000), 98 );
+        assert_eq!( hq_percent(1000, 1000), 100 );
+        assert_eq!( hq_percent(1200, 1000), 100 );
+
+        // 区切りの間は補間せず、切り捨てた割合の段のHQ率のままです
+        assert_eq!( (hq_percent(695, 1000), hq_percent(699, 1000)), (26, 26) );
+        assert_eq!( (hq_percent(705, 1000), hq_percent(709, 1000)), (28, 28) );
+        assert_eq!( hq_percent(9, 1000), 1 );
+    }
+}
@@ -0,0 +1,495 @@
+use std::cell::{Cell,RefCell};
+use std::collections::HashMap;
+use std::hash::Hash;
+use core::cmp::{min,max,Ordering};
+use core::ops::{Add,Mul};
+use super::logic::{State,Action,Setting};
+use super::statespace::StateSpace;
+use super::table::{ValueSource,Policy,calc_value};
+use super::formula::hq_percent;
+
+// 遅延評価で覚えるキーです。計算し直す時はCPの低い順に並べます
+trait MemoKey : Copy + Eq + Hash {
+    fn cp(&self) -> u16;
+}
+
+impl MemoKey for State {
+    fn cp(&self) -> u16 {
+        self.cp
+    }
+}
+
+impl MemoKey for (State,u32) {
+    fn cp(&self) -> u16 {
+        self.0.cp
+    }
+}
+
+// 遅延評価で覚えた、キーごとの選んだアクションと評価値です。
+// 秘訣の遷移先(CPの高い状態)は表と同じく最初は使わずに評価して、その後で覚えた状態を評価値が変わらなくなるまで計算し直します
+struct Memo<K,V> {
+    memo : RefCell<HashMap<K,(Action,V)>>,
+    restores : Cell<bool>, // CPの高い状態を参照したかどうか
+}
+
+impl<K: MemoKey, V: Copy> Memo<K,V> {
+    fn new() -> Memo<K,V> {
+        Memo { memo:RefCell::new(HashMap::new()), restores:Cell::new(false) }
+    }
+
+    fn len(&self) -> usize {
+        self.memo.borrow().len()
+    }
+
+    fn get(&self, k:&K) -> Option<(Action,V)> {
+        self.memo.borrow().get(k).copied()
+    }
+
+    // 最初の評価です。覚えているキーはそのまま返します
+    fn evaluate(&self, k:&K, calc:impl FnOnce() -> (Action,V)) -> (Action,V) {
+        if let Some(x) = self.get(k) {
+            return x;
+        }
+
+        let x = calc();
+        self.memo.borrow_mut().insert( *k, x );
+        x
+    }
+
+    // 評価している状態から見た参照先の評価です。最初の評価では、CPの高い状態はまだ評価していなければ使えません
+    fn refer(&self, from:&State, k:&K, first:bool, evaluate:impl FnOnce() -> (Action,V)) -> Option<(Action,V)> {
+        if k.cp() > from.cp {
+            self.restores.set(true);
+            if first {
+                return self.get(k);
+            }
+        }
+        Some(evaluate())
+    }
+
+    // キーを評価して、秘訣の遷移先を参照した場合は覚えたキーをCPの低い順に、評価値が変わらず新しいキーも増えなくなるまで計算し直します。
+    // calcの2つ目の引数は最初の評価かどうかで、sameは評価が変わっていないかどうかです
+    fn solve(&self, k:&K, calc:impl Fn(&K,bool) -> (Action,V), same:impl Fn(&(Action,V),&(Action,V)) -> bool) -> (Action,V) {
+        self.evaluate(k, || calc(k, true));
+        while self.restores.get() {
+            let mut keys : Vec<K> = self.memo.borrow().keys().copied().collect();
+            keys.sort_by_key(|k| k.cp());
+
+            let mut changed = false;
+            for k in &keys {
+                let x = calc(k, false);
+                changed |= self.memo.borrow_mut().insert( *k, x ).is_none_or(|y| !same(&y, &x));
+            }
+            if !changed && self.len() == keys.len() {
+                break;
+            }
+        }
+        self.memo.borrow()[k]
+    }
+}
+
+// 状態空間は表と同じ設定で作ります。表の範囲を超えるCPや耐久を調べる場合は、その範囲まで広げた設定の表と同じ答えになります
+fn widen( setting:&Setting, s:&State ) -> Setting {
+    Setting {
+        max_cp: max(setting.max_cp, s.cp),
+        max_durability: max(setting.max_durability, s.durability),
+        ..setting.clone()
+    }
+}
+
+// 1つの状態だけを調べる場合の遅延評価です。表を全て埋める代わりに、調べる状態から遷移できる状態だけを再帰的に評価してハッシュマップに覚えておきます
+pub struct LazySolver {
+    setting : Setting,
+    space : StateSpace,
+    memo : Memo<State,u32>,
+}
+
+// 評価している状態から見た参照先です
+struct View<'a> {
+    solver : &'a LazySolver,
+    from : State,
+    first : bool,
+}
+
+impl ValueSource for View<'_> {
+    fn value(&self, s:&State, _index:usize) -> Option<u32> {
+        self.solver.memo.refer( &self.from, s, self.first, || self.solver.evaluate(s) ).map(|x| x.1)
+    }
+}
+
+impl LazySolver {
+    pub fn new( setting:&Setting, s:&State ) -> Result<LazySolver,String> {
+        let setting = widen( setting, s );
+        let space = StateSpace::new(&setting)?;
+        Ok(LazySolver { setting, space, memo:Memo::new() })
+    }
+
+    pub fn setting(&self) -> &Setting {
+        &self.setting
+    }
+
+    // 評価した状態の数です
+    pub fn num_evaluated(&self) -> usize {
+        self.memo.len()
+    }
+
+    fn calc_value(&self, s:&State, first:bool) -> (Action,u32) {
+        calc_value( &self.setting, &View { solver:self, from:*s, first }, &self.space, s )
+    }
+
+    fn evaluate(&self, s:&State) -> (Action,u32) {
+        self.memo.evaluate( s, || self.calc_value( s, true ) )
+    }
+
+    fn solve(&self, s:&State) -> (Action,u32) {
+        self.memo.solve( s, |s,first| self.calc_value( s, first ), |x,y| x == y )
+    }
+}
+
+impl Policy for LazySolver {
+    fn contains(&self, s:&State) -> bool {
+        self.space.contains(s)
+    }
+
+    fn is_completed(&self, s:&State) -> bool {
+        self.space.is_completed(s)
+    }
+
+    // 計算し直した後の状態は覚えている値がそのまま答えです
+    fn action(&self, s:&State) -> Action {
+        self.memo.get(s).unwrap_or_else(|| self.solve(s)).0
+    }
+}
+
+// 品質に上限や収集品の段階がある場合の評価値です。最後の品質の効用の期待値を主に、最後に残るCPと耐久の合計の期待値を従にして比べます
+// 期待値を取る時の効用です。収集品の段階がある場合は開始品質を足した品質で届く段階の報酬を、
+// HQ率を最大化する場合は開始品質を足した品質のゲームのHQ率の表の値(最高品質に対する割合1%ごとの段)を主にします。
+// どちらも平らな区間があるので、品質そのものも足して狭義単調増加にしておきます(逆関数で品質が潰れないように)
+pub fn utility( setting:&Setting, q:u64 ) -> u64 {
+    if setting.collects() {
+        let quality = setting.initial_quality as u64 + q;
+        let max_quality = setting.initial_quality as u64 + setting.quality_cap().unwrap_or(0) as u64;
+        setting.tier_reward(quality as u32) as u64 * (max_quality + 1) + quality
+    }
+    else if setting.maximizes_hq() {
+        let max_quality = setting.max_quality as u64;
+        let quality = setting.initial_quality as u64 + q;
+        hq_percent(quality as u32, max_quality as u32) as u64 * (max_quality + 1) + quality
+    }
+    else {
+        q
+    }
+}
+
+// 効用の期待値を、同じ効用になる品質に戻します(効用がそれ以上になる最小の品質を二分探索します)
+pub fn from_utility( setting:&Setting, u:u64 ) -> u64 {
+    if setting.collects() || setting.maximizes_hq() {
+        let (mut lo, mut hi) = (0, setting.quality_cap().unwrap_or(0) as u64);
+        while lo < hi {
+            let mid = (lo + hi) / 2;
+            if utility(setting, mid) >= u { hi = mid } else { lo = mid + 1 }
+        }
+        lo
+    }
+    else {
+        u
+    }
+}
+
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub struct Expected {
+    pub utility : f64,
+    pub leftover : f64,
+}
+
+impl Add for Expected {
+    type Output = Expected;
+
+    fn add(self, x:Expected) -> Expected {
+        Expected { utility:self.utility + x.utility, leftover:self.leftover + x.leftover }
+    }
+}
+
+impl Mul<f64> for Expected {
+    type Output = Expected;
+
+    fn mul(self, p:f64) -> Expected {
+        Expected { utility:self.utility * p, leftover:self.leftover * p }
+    }
+}
+
+impl Expected {
+    const ZERO : Expected = Expected { utility:0.0, leftover:0.0 };
+
+    // 同じ値を違う順番で足した誤差で差がつかないように、小さな差は同じとみなします
+    pub fn is_better_than(&self, x:&Expected) -> bool {
+        let same = |a:f64, b:f64| (a - b).abs() <= 1e-9 * a.abs().max(b.abs()).max(1.0);
+        if !same(self.utility, x.utility) { self.utility > x.utility } else { !same(self.leftover, x.leftover) && self.leftover > x.leftover }
+    }
+
+    // 品質をdqだけずらした評価値です。効用が「品質 + 定数」になる区間の中でだけ使えます
+    fn shift(self, dq:i64) -> Expected {
+        Expected { utility:self.utility + dq as f64, ..self }
+    }
+}
+
+// 品質に上限や収集品の段階がある場合の遅延評価です。表の状態は今までに上げた品質を持たないので、上限までの残りや届く段階が分かりません。
+// ここでは状態と仕上げを始めてから上げた品質(上限で頭打ち)の組ごとに、最後の品質の効用と残るCPと耐久の期待値を評価します。
+// 期待値は丸めずに実数で持ちます
+pub struct QualitySolver {
+    setting : Setting,
+    space : StateSpace,
+    cap : u32,
+    memo : Memo<(State,u32),Option<Expected>>,
+    max_gains : RefCell<HashMap<State,Option<u32>>>, // 上げられる品質の上限(計算中はNone)
+}
+
+impl QualitySolver {
+    // 品質に上限が無い設定では使いません
+    pub fn new( setting:&Setting, s:&State ) -> Result<QualitySolver,String> {
+        let setting = widen( setting, s );
+        let space = StateSpace::new(&setting)?;
+        let cap = setting.quality_cap().expect("quality cap");
+        Ok(QualitySolver { setting, space, cap, memo:Memo::new(), max_gains:RefCell::new(HashMap::new()) })
+    }
+
+    pub fn setting(&self) -> &Setting {
+        &self.setting
+    }
+
+    // 評価した状態と品質の組の数です
+    pub fn num_evaluated(&self) -> usize {
+        self.memo.len()
+    }
+
+    // 製作を終えた時の評価値です。上限に届く手順同士では、CPと耐久を多く残す方が選ばれます
+    fn finished(&self, s:&State, q:u32) -> Expected {
+        Expected { utility:utility(&self.setting, min(q, self.cap) as u64) as f64, leftover:(s.cp as u64 + s.durability as u64) as f64 }
+    }
+
+    // 全ての成否と状態(コンディション)が最も良い方に続いた場合に、これから上げられる品質です。
+    // 秘訣で遷移が循環する場合は上限なし(u32::MAX)とします
+    fn max_gain(&self, s:&State) -> u32 {
+        if let Some(x) = self.max_gains.borrow().get(s) {
+            return x.unwrap_or(u32::MAX);
+        }
+        self.max_gains.borrow_mut().insert( *s, None );
+
+        let mut gain = 0;
+        for a in self.setting.rules().candidate_actions().iter().filter(|a| s.check_action( &self.setting, a )) {
+            for (_,ns,dq) in s.run_action( &self.setting, a ).iter() {
+                if self.space.is_completed(&ns) {
+                    gain = max(gain, dq);
+                }
+                else if self.space.get_base_index(&ns).is_some() {
+                    for (c,_) in s.condition.next( &self.setting, a ) {
+                        gain = max(gain, dq.saturating_add(self.max_gain( &State { condition:*c, ..ns } )));
+                    }
+                }
+            }
+        }
+        self.max_gains.borrow_mut().insert( *s, Some(gain) );
+        gain
+    }
+
+    // 効用が「品質 + 定数」になる区間のうち、qを含む区間の一番上の品質です。効用から品質を引いた値は品質について単調なので二分探索します
+    fn linear_until(&self, q:u32) -> u32 {
+        let offset = |x:u32| utility(&self.setting, x as u64) - x as u64;
+        let (mut lo, mut hi) = (q, self.cap);
+        while lo < hi {
+            let mid = (lo + hi).div_ceil(2);
+            if offset(mid) == offset(q) { lo = mid } else { hi = mid - 1 }
+        }
+        lo
+    }
+
+    // 評価を覚える時の品質です。これから上げられる品質の上限を足しても効用が「品質 + 定数」のままなら、
+    // 評価値は上げた品質だけずれて手順は変わらないので、その区間に収まる一番上の品質にまとめます
+    fn key(&self, s:&State, q:u32) -> u32 {
+        let top = self.linear_until(q);
+        match self.max_gain(s) {
+            gain if gain <= top - q => top - gain,
+            _ => q,
+        }
+    }
+
+    // 覚えた評価を、まとめる前の品質の評価に戻します
+    fn unshift(x:(Action,Option<Expected>), q:u32, key:u32) -> (Action,Option<Expected>) {
+        (x.0, x.1.map(|v| v.shift(q as i64 - key as i64)))
+    }
+
+    fn value(&self, from:&State, s:&State, q:u32, first:bool) -> Option<Expected> {
+        let key = self.key(s, q);
+        let x = self.memo.refer( from, &(*s,key), first, || self.memo.evaluate( &(*s,key), || self.calc_value( s, key, true ) ) )?;
+        Self::unshift(x, q, key).1
+    }
+
+    // アクションの成否と次の工程の状態(コンディション)について期待値を計算します。表のexpected_valueと同じく、
+    // 完成した状態や、工数を追跡せずに上限に届いた状態はそこで製作を終えます。使えない遷移があればNoneです
+    fn expected(&self, s:&State, q:u32, a:&Action, first:bool) -> Option<Expected> {
+        let mut sum = Expected::ZERO;
+        for (p,ns,dq) in s.run_action( &self.setting, a ).iter() {
+            let nq = min(q + dq, self.cap);
+            let v = if self.space.is_completed(&ns) {
+                self.finished(&ns, nq)
+            }
+            else {
+                self.space.get_base_index(&ns)?;
+                if nq >= self.cap && !self.setting.tracks_progress() && self.setting.can_finish(&ns) {
+                    self.finished(&ns, nq)
+                }
+                else {
+                    let mut sum = Expected::ZERO;
+                    for (c,pc) in s.condition.next( &self.setting, a ) {
+                        sum = sum + self.value( s, &State { condition:*c, ..ns }, nq, first )? * (*pc as f64 / 100.0);
+                    }
+                    sum
+                }
+            };
+            sum = sum + v * (p as f64 / 100.0);
+        }
+        Some(sum)
+    }
+
+    // 工数を追跡する場合や、残すCPと耐久が足りない場合は、何もせずに終わると失敗になります
+    fn calc_value(&self, s:&State, q:u32, first:bool) -> (Action,Option<Expected>) {
+        let mut best = (Action::CannotAction, if self.space.progress > 0 || !self.setting.can_finish(s) { None } else { Some(self.finished(s, q)) });
+        for a in self.setting.rules().candidate_actions() {
+            if s.check_action( &self.setting, a ) {
+                if let Some(v) = self.expected( s, q, a, first ) {
+                    if best.1.is_none_or(|x| v.is_better_than(&x)) {
+                        best = (*a, Some(v));
+                    }
+                }
+            }
+        }
+        best
+    }
+
+    // 状態と上げた品質を評価します
+    pub fn solve(&self, s:&State, q:u32) -> (Action,Option<Expected>) {
+        let q = min(q, self.cap);
+        let key = self.key(s, q);
+        let same = |x:&(Action,Option<Expected>), y:&(Action,Option<Expected>)| x.0 == y.0 && match (x.1, y.1) {
+            (Some(a), Some(b)) => !a.is_better_than(&b) && !b.is_better_than(&a),
+            (a, b) => a.is_some() == b.is_some(),
+        };
+        Self::unshift(self.memo.solve( &(*s,key), |(s,q),first| self.calc_value( s, *q, first ), same ), q, key)
+    }
+
+    // 状態で使えるアクションを、その後は最善の手順を続けた場合の評価値の高い順に並べて、上位k個を返します
+    pub fn best_actions(&self, s:&State, q:u32, k:usize) -> Vec<(Action,Expected)> {
+        let q = min(q, self.cap);
+        self.solve(s, q);
+        let mut actions : Vec<(Action,Expected)> = self.setting.rules().candidate_actions().iter().filter(|a| s.check_action( &self.setting, a )).filter_map(|a| {
+            Some((*a, self.expected( s, q, a, false )?))
+        }).collect();
+
+        actions.sort_by(|(_,x),(_,y)| if x.is_better_than(y) { Ordering::Less } else if y.is_better_than(x) { Ordering::Greater } else { Ordering::Equal });
+        actions.truncate(k);
+        actions
+    }
+}
+
+impl Policy for QualitySolver {
+    fn contains(&self, s:&State) -> bool {
+        self.space.contains(s)
+    }
+
+    fn is_completed(&self, s:&State) -> bool {
+        self.space.is_completed(s)
+    }
+
+    fn action(&self, s:&State) -> Action {
+        self.action_at(s, 0)
+    }
+
+    fn action_at(&self, s:&State, quality:u32) -> Action {
+        let q = min(quality, self.cap);
+        self.memo.get( &(*s,self.key(s, q)) ).unwrap_or_else(|| self.solve(s, q)).0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logic::{Buff,Condition,CollectabilityTier,Objective};
+    use crate::rules::Rules;
+    use crate::table::calc_table;
+
+    // 遅延評価は同じ設定の表と同じアクションを選びます(秘訣の計算し直しや、同じCPの中での遷移を含みます)
+    #[test]
+    fn same_actions_as_table() {
+        let setting = Setting { conditions:true, ..Setting::example( Rules::Patch5, 40, 20 ) };
+        let (_,ta) = calc_table( &setting ).unwrap();
+        for (cp,durability,inner_quiet) in [(0,20,5),(20,20,11),(40,20,11),(40,10,3)] {
+            for condition in Condition::ALL {
+                let s = State { cp, durability, progress:0, condition, buff: Buff { inner_quiet, ..Default::default() } };
+                let solver = LazySolver::new( &setting, &s ).unwrap();
+                assert_eq!( solver.action(&s), ta[s] );
+            }
+        }
+    }
+
+    // 品質に上限がある場合は、上げた品質で選ぶアクションが変わります。上限に届いていればCPと耐久を残してそこで終わります
+    #[test]
+    fn quality_solver_stops_at_cap() {
+        let setting = Setting { max_quality:1000, ..Setting::example( Rules::Patch5, 40, 20 ) };
+        let s = State { cp:40, durability:20, progress:0, condition:Condition::Normal, buff: Buff { inner_quiet:5, ..Default::default() } };
+        let solver = QualitySolver::new( &setting, &s ).unwrap();
+        assert_ne!( solver.action_at(&s, 0), Action::CannotAction );
+        assert_eq!( solver.action_at(&s, 1000), Action::CannotAction );
+
+        let (_,v) = solver.solve(&s, 1000);
+        assert_eq!( v.map(|v| v.leftover), Some(60.0) );
+    }
+
+    // 収集品の段階がある場合、最も高い段階を越える品質には価値がありません。
+    // ヘイスティタッチを2回試せば段階にはほぼ届くので、評価値は段階の品質になります
+    #[test]
+    fn collectability_tier_caps_value() {
+        let setting = Setting { collectability_tiers: vec![CollectabilityTier { collectability:10, reward:5 }], ..Setting::example( Rules::Patch5, 0, 20 ) };
+        let s = State::example( 0, 20, Buff { inner_quiet:11, ..Default::default() } );
+        let solver = QualitySolver::new( &setting, &s ).unwrap();
+        let (a,v) = solver.solve(&s, 0);
+        assert_eq!( (a, v.map(|v| from_utility( &setting, v.utility as u64 ))), (Action::HastyTouch, Some(100)) );
+    }
+
+    // 開始品質の分だけ上げられる品質が減ります。上限に達していれば何もせずに終わります
+    #[test]
+    fn starting_quality_under_cap() {
+        let s = State::example( 0, 20, Buff { inner_quiet:11, ..Default::default() } );
+        let setting = Setting { max_quality:1000, ..Setting::example( Rules::Patch5, 0, 20 ) };
+        assert_eq!( QualitySolver::new( &setting, &s ).unwrap().action_at(&s, 0), Action::HastyTouch );
+
+        let setting = Setting { initial_quality:1000, ..setting };
+        assert_eq!( setting.quality_cap(), Some(0) );
+        assert_eq!( QualitySolver::new( &setting, &s ).unwrap().action_at(&s, 0), Action::CannotAction );
+    }
+
+    // HQ率はゲームの表の通り段ごとに一定で、同じ段の中では品質だけで比べます
+    #[test]
+    fn hq_utility_steps() {
+        let setting = Setting { max_quality:1000, objective:Objective::HqPercent, ..Setting::example( Rules::Patch5, 24, 20 ) };
+        let step = 26 * 1001;
+        assert_eq!( (utility( &setting, 690 ), utility( &setting, 699 )), (step + 690, step + 699) );
+        assert_eq!( utility( &setting, 700 ), 28 * 1001 + 700 );
+        assert_eq!( from_utility( &setting, step + 695 ), 695 );
+    }
+
+    // HQ率を最大化する場合は、品質の期待値では劣るアクションでもHQ率の期待値が高ければ選びます
+    #[test]
+    fn hq_objective_changes_best_action() {
+        let s = State::example( 20, 20, Buff { inner_quiet:11, ..Default::default() } );
+        let quality = Setting { max_quality:10000, initial_quality:3000, ..Setting::example( Rules::Patch5, 24, 20 ) };
+        let hq = Setting { objective:Objective::HqPercent, ..quality.clone() };
+        let qs = QualitySolver::new( &quality, &s ).unwrap();
+        let hs = QualitySolver::new( &hq, &s ).unwrap();
+        assert_eq!( (qs.action_at(&s, 0), hs.action_at(&s, 0)), (Action::BasicTouch, Action::Innovation) );
+
+        // それぞれの目的で、もう一方の選んだアクションの方が劣ります
+        let value = |solver:&QualitySolver, a:Action| solver.best_actions( &s, 0, usize::MAX ).into_iter().find(|x| x.0 == a).unwrap().1;
+        assert!( value( &qs, Action::BasicTouch ).is_better_than( &value( &qs, Action::Innovation ) ) );
+        assert!( value( &hs, Action::Innovation ).is_better_than( &value( &hs, Action::BasicTouch ) ) );
+    }
+}
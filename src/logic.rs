@@ -1,6 +1,8 @@
 use core::cmp::min;
+use super::rules::{Rules,CraftRules};
+use super::formula::QualityFormula;
 
-#[derive(PartialEq,Eq,Hash,Clone,Debug,Copy)]
+#[derive(PartialEq,Eq,Hash,Clone,Debug,Copy,Default)]
 pub struct Buff {
     pub inner_quiet : u8,
     pub manipulation : u8,
@@ -8,25 +10,87 @@ pub struct Buff {
     pub great_strides : u8,
     pub waste_not : u8,
     pub basic_touch : u8,
+    pub standard_touch : u8,
     pub observe : u8,
+    pub veneration : u8,
+    pub name_of_the_elements : u8,
+    pub name_of_the_elements_used : u8,
+    pub expedience : u8,
+    pub trained_perfection : u8,
+    pub trained_perfection_used : u8,
+    pub quick_innovation_used : u8,
+    pub immaculate_mend_used : u8,
+    pub careful_observation_used : u8,
+    pub heart_and_soul : u8,
+    pub heart_and_soul_used : u8,
+    pub first_step : u8,
+    pub muscle_memory : u8,
+    pub final_appraisal : u8,
+}
+
+#[derive(PartialEq,Eq,Hash,Clone,Debug,Copy)]
+pub enum Condition {
+    Normal,             // 通常
+    Good,               // 高品質
+    Excellent,          // 最高品質
+    Poor,               // 低品質
 }
 
 #[derive(PartialEq,Eq,Hash,Clone,Debug,Copy)]
 pub struct State {
     pub durability: u8,
     pub cp: u16,
+    pub progress: u16,
+    pub condition: Condition,
     pub buff: Buff,
 }
 
+// 収集品の段階です。収集価値(品質の1/10)が閾値以上なら報酬(スクリップなど)がもらえます
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub struct CollectabilityTier {
+    pub collectability: u32,
+    pub reward: u32,
+}
+
+impl CollectabilityTier {
+    // 段階に届くのに必要な品質です
+    pub fn quality(&self) -> u32 {
+        self.collectability * 10
+    }
+}
+
+// 表の計算で最大化するものです
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum Objective {
+    Quality,            // 品質
+    HqPercent,          // HQ率(最高品質が必要です)
+}
+
+#[derive(Clone,Debug,PartialEq)]
 pub struct Setting {
     pub max_durability: u8,
     pub max_cp: u16,
     pub sustain: bool,
+    pub specialist: bool,
+    pub conditions: bool,
     pub process_accuracy: u32,
     pub required_process_accuracy: u32,
+    pub craftsmanship: u32,
+    pub required_craftsmanship: u32,
+    pub difficulty: u32,
+    pub max_quality: u32,
+    pub initial_quality: u32,
+    pub collectability_tiers: Vec<CollectabilityTier>,
+    pub objective: Objective,
+    pub reserve_cp: u16,
+    pub reserve_durability: u8,
+    pub crafter_level: u8,
+    pub quality_formula: QualityFormula,
+    pub rules: Rules,
 }
 
-#[derive(Debug,Clone,Copy,PartialEq)]
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Hash)]
+#[allow(clippy::enum_variant_names)]
 pub enum Action {
     CannotAction,       // 選択肢が他に無い場合
     BasicTouch,         // 加工
@@ -42,6 +106,273 @@ pub enum Action {
     GreatStrides,       // グレートストライド
     Innovation,         // イノベーション
     Manipulation,       // マニピュレーション
+    InnerQuiet,         // インナークワイエット
+    PreciseTouch,       // 集中加工
+    TricksOfTheTrade,   // 秘訣
+    HastyTouch,         // ヘイスティタッチ
+    PatientTouch,       // 専心加工
+    BasicSynthesis,     // 作業
+    CarefulSynthesis,   // 模範作業
+    RapidSynthesis,     // 突貫作業
+    IntensiveSynthesis, // 集中作業
+    Groundwork,         // 下地作業
+    DelicateSynthesis,  // 精密作業
+    BrandOfTheElements, // ブランド・オブ・エレメント
+    Veneration,         // ヴェネレーション
+    NameOfTheElements,  // ネーム・オブ・エレメント
+    AdvancedTouch,      // 上級加工
+    TrainedFinesse,     // 匠の絶技
+    PrudentSynthesis,   // 倹約作業
+    RefinedTouch,       // 洗練加工
+    DaringTouch,        // デアリングタッチ
+    QuickInnovation,    // クイックイノベーション
+    TrainedPerfection,  // 匠の神業
+    ImmaculateMend,     // パーフェクトメンド
+    CarefulObservation, // 設計変更
+    HeartAndSoul,       // 一心不乱
+    Reflect,            // 確信
+    MuscleMemory,       // 確信作業
+    TrainedEye,         // 匠の早業
+    FinalAppraisal,     // 最終確認
+    Data(u8),           // データファイルで定義したアクション(定義の番号)
+}
+
+// アクションの結果の分布です。成功率が100%未満のアクションだけ失敗時の状態を持ちます。
+#[derive(Debug,Clone,Copy)]
+pub struct Outcomes {
+    pub success: (State,u32),   // 成功時の状態と品質
+    pub failure: Option<State>, // 失敗時の状態(品質は上がりません)
+    pub success_rate: u32,      // 成功率(%)
+}
+
+impl Outcomes {
+    // (確率(%),状態,品質)の組を列挙します
+    pub fn iter(&self) -> impl Iterator<Item=(u32,State,u32)> + '_ {
+        let (s,q) = self.success;
+        std::iter::once((self.success_rate,s,q)).chain(self.failure.iter().map(move |f| (100 - self.success_rate,*f,0)))
+    }
+
+    // 起こりやすい方の結果です。五分五分の場合は成功を選びます
+    pub fn most_likely(&self) -> (State,u32) {
+        match self.failure {
+            Some(f) if self.success_rate < 50 => (f,0),
+            _ => self.success,
+        }
+    }
+}
+
+impl Setting {
+    pub fn rules(&self) -> &'static dyn CraftRules {
+        self.rules.get()
+    }
+
+    // 工数は効率10%分を1単位として数えます。
+    // 完成に必要な単位数を返します。工数が0の場合は仕上げだけを扱うので工数を追跡しません
+    pub fn required_progress(&self) -> u16 {
+        if self.difficulty == 0 {
+            0
+        }
+        else {
+            (self.difficulty as f64 * 10.0 / self.rules().base_progress(self)).ceil() as u16
+        }
+    }
+
+    // 工数を単位数に換算します(切り捨て)
+    pub fn progress_units(&self, progress:u32) -> u16 {
+        if self.difficulty == 0 {
+            0
+        }
+        else {
+            (progress as f64 * 10.0 / self.rules().base_progress(self)) as u16
+        }
+    }
+
+    // レシピのクラスレベルです。レシピレベルの表を使う場合だけ分かります
+    pub fn recipe_class_level(&self) -> Option<u8> {
+        match self.quality_formula {
+            QualityFormula::Exact(r) => Some(r.class_level),
+            QualityFormula::Approximate => None,
+        }
+    }
+
+    // これから上げられる品質の上限です。収集品の段階がある場合は最も高い段階の品質までしか上げる意味がありません。
+    // 最高品質が0で段階も無い場合は上限を設けません
+    pub fn quality_cap(&self) -> Option<u32> {
+        let max_quality = match self.collectability_tiers.iter().map(|t| t.quality()).max() {
+            Some(q) if self.max_quality > 0 => min(q, self.max_quality),
+            Some(q) => q,
+            None => self.max_quality,
+        };
+        if max_quality == 0 { None } else { Some(max_quality.saturating_sub(self.initial_quality)) }
+    }
+
+    // 表を計算する設定です。表は上げた品質の期待値だけを最大化するので、品質の上限に関わる設定(最高品質、開始品質、収集品の段階、目的)は使いません。
+    // 上限がある場合は表を使わずに遅延評価で答えます
+    pub fn table_setting(&self) -> Setting {
+        Setting { max_quality:0, initial_quality:0, collectability_tiers:vec![], objective:Objective::Quality, ..self.clone() }
+    }
+
+    // 収集品の段階で報酬を最大化するかどうかです
+    pub fn collects(&self) -> bool {
+        !self.collectability_tiers.is_empty()
+    }
+
+    // 品質で届く段階のうち最も多い報酬です。どの段階にも届かない場合は0です
+    pub fn tier_reward(&self, quality:u32) -> u32 {
+        self.collectability_tiers.iter().filter(|t| quality >= t.quality()).map(|t| t.reward).max().unwrap_or(0)
+    }
+
+    // 仕上げの後には完成させる作業が続くので、その分のCPと耐久を残して終わる必要があります。
+    // 完成した状態はそこで製作が終わるので対象外です
+    pub fn can_finish(&self, s:&State) -> bool {
+        s.cp >= self.reserve_cp && s.durability >= self.reserve_durability
+    }
+
+    // HQ率を最大化するかどうかです。最高品質が分からない場合は品質を、収集品の段階がある場合は報酬を最大化します
+    pub fn maximizes_hq(&self) -> bool {
+        self.objective == Objective::HqPercent && self.max_quality > 0 && !self.collects()
+    }
+
+    pub fn tracks_progress(&self) -> bool {
+        self.difficulty > 0
+    }
+
+    // 倹約、マニピュレーション、イノベーション、グレートストライドの効果時間です。sustainが有効な場合は2工程延長されます。
+    // 最終確認、確信、ヴェネレーションなどの他のバフは延長されません
+    pub fn buff_duration(&self, x:u8) -> u8 {
+        if self.sustain { x + 2 } else { x }
+    }
+}
+
+// テストで使う設定です。ステータスはmain.rsの最初の設定と同じで、状態(コンディション)は使いません
+#[cfg(test)]
+impl Setting {
+    pub fn example( rules:Rules, max_cp:u16, max_durability:u8 ) -> Setting {
+        Setting {
+            max_durability,
+            max_cp,
+            sustain: false,
+            specialist: false,
+            conditions: false,
+            process_accuracy: 2910,
+            required_process_accuracy: 2540,
+            craftsmanship: 2763,
+            required_craftsmanship: 2480,
+            difficulty: 0,
+            max_quality: 0,
+            initial_quality: 0,
+            collectability_tiers: vec![],
+            objective: Objective::Quality,
+            reserve_cp: 0,
+            reserve_durability: 0,
+            crafter_level: 80,
+            quality_formula: QualityFormula::Approximate,
+            rules,
+        }
+    }
+}
+
+// テストで使う状態です。工数は0で、状態(コンディション)は通常です
+#[cfg(test)]
+impl State {
+    pub fn example( cp:u16, durability:u8, buff:Buff ) -> State {
+        State { cp, durability, progress:0, condition:Condition::Normal, buff }
+    }
+}
+
+impl Condition {
+    pub const ALL : [Condition;4] = [Condition::Normal, Condition::Good, Condition::Excellent, Condition::Poor];
+
+    // 高品質と最高品質の時だけ使えるアクションがあります
+    pub fn is_good(&self) -> bool {
+        matches!(self, Condition::Good | Condition::Excellent)
+    }
+
+    // アクション後の次の工程の状態とその確率(%)です。遷移の確率はルールごとに異なります
+    // 工程を進めないアクションの後は状態が変わりません
+    pub fn next(&self, setting:&Setting, a:&Action) -> &'static [(Condition,u32)] {
+        if !setting.conditions {
+            return &[(Condition::Normal,100)];
+        }
+
+        if setting.rules().keeps_condition(a) {
+            return match self {
+                Condition::Normal => &[(Condition::Normal,100)],
+                Condition::Good => &[(Condition::Good,100)],
+                Condition::Excellent => &[(Condition::Excellent,100)],
+                Condition::Poor => &[(Condition::Poor,100)],
+            };
+        }
+
+        setting.rules().condition_transitions(self)
+    }
+
+    // 一番起こりやすい次の状態です
+    pub fn most_likely_next(&self, setting:&Setting, a:&Action) -> Condition {
+        self.next(setting, a).iter().max_by_key(|(_,p)| *p).unwrap().0
+    }
+}
+
+impl Action {
+    // 作業系のアクションかどうかです。工数を追跡しない場合は使いません
+    pub fn is_synthesis(&self) -> bool {
+        matches!(self, Action::BasicSynthesis | Action::CarefulSynthesis | Action::RapidSynthesis | Action::IntensiveSynthesis | Action::Groundwork |
+            Action::DelicateSynthesis | Action::BrandOfTheElements | Action::Veneration | Action::NameOfTheElements | Action::PrudentSynthesis | Action::MuscleMemory)
+    }
+
+    // マイスターだけが使えるアクションです
+    pub fn is_specialist(&self) -> bool {
+        matches!(self, Action::CarefulObservation | Action::HeartAndSoul | Action::QuickInnovation)
+    }
+
+    // アクションを習得するクラフターのレベルです。データファイルのアクションはルールの側で判定します
+    pub fn level(&self) -> u8 {
+        match self {
+            Action::CannotAction => 0,
+            Action::BasicSynthesis => 1,
+            Action::BasicTouch => 5,
+            Action::MastersMend => 7,
+            Action::HastyTouch => 9,
+            Action::RapidSynthesis => 9,
+            Action::InnerQuiet => 11,
+            Action::Observe => 13,
+            Action::TricksOfTheTrade => 13,
+            Action::WasteNot => 15,
+            Action::Veneration => 15,
+            Action::StandardTouch => 18,
+            Action::GreatStrides => 21,
+            Action::Innovation => 26,
+            Action::NameOfTheElements => 37,
+            Action::BrandOfTheElements => 37,
+            Action::FinalAppraisal => 42,
+            Action::WasteNot2 => 47,
+            Action::ByregotsBlessing => 50,
+            Action::PreciseTouch => 53,
+            Action::MuscleMemory => 54,
+            Action::CarefulObservation => 55,
+            Action::CarefulSynthesis => 62,
+            Action::PatientTouch => 64,
+            Action::Manipulation => 65,
+            Action::PrudentTouch => 66,
+            Action::FocusedTouch => 68,
+            Action::Reflect => 69,
+            Action::PreparatoryTouch => 71,
+            Action::Groundwork => 72,
+            Action::DelicateSynthesis => 76,
+            Action::IntensiveSynthesis => 78,
+            Action::TrainedEye => 80,
+            Action::AdvancedTouch => 84,
+            Action::HeartAndSoul => 86,
+            Action::PrudentSynthesis => 88,
+            Action::TrainedFinesse => 90,
+            Action::RefinedTouch => 92,
+            Action::DaringTouch => 96,
+            Action::QuickInnovation => 96,
+            Action::ImmaculateMend => 98,
+            Action::TrainedPerfection => 100,
+            Action::Data(_) => 0,
+        }
+    }
 }
 
 trait ClippedSubtract<T> {
@@ -50,218 +381,344 @@ trait ClippedSubtract<T> {
 
 impl ClippedSubtract<u8> for u8 {
     fn sub_clip(&self,x:u8) -> u8 {
-        if *self >= x { *self - x } else { 0 }
+        self.saturating_sub(x)
     }
 }
 
 impl Buff {
+    // 状態空間のインデックス計算に使うフィールドの数です
+    pub const NUM_FIELDS : usize = 22;
+
+    // データファイルからフィールドを指定する時の名前です。並びはfieldsと同じです
+    pub const FIELD_NAMES : [&'static str;Buff::NUM_FIELDS] = [
+        "inner_quiet",
+        "manipulation",
+        "innovation",
+        "great_strides",
+        "waste_not",
+        "basic_touch",
+        "standard_touch",
+        "observe",
+        "veneration",
+        "name_of_the_elements",
+        "name_of_the_elements_used",
+        "expedience",
+        "trained_perfection",
+        "trained_perfection_used",
+        "quick_innovation_used",
+        "immaculate_mend_used",
+        "careful_observation_used",
+        "heart_and_soul",
+        "heart_and_soul_used",
+        "first_step",
+        "muscle_memory",
+        "final_appraisal",
+    ];
+
+    // sustainで効果時間が延長されるフィールド(マニピュレーション、イノベーション、グレートストライド、倹約)です
+    pub fn is_sustained(i:usize) -> bool {
+        (1..=4).contains(&i)
+    }
+
+    pub fn field_index(name:&str) -> Option<usize> {
+        Buff::FIELD_NAMES.iter().position(|n| *n == name)
+    }
+
+    pub fn field_mut(&mut self, i:usize) -> &mut u8 {
+        match i {
+            0 => &mut self.inner_quiet,
+            1 => &mut self.manipulation,
+            2 => &mut self.innovation,
+            3 => &mut self.great_strides,
+            4 => &mut self.waste_not,
+            5 => &mut self.basic_touch,
+            6 => &mut self.standard_touch,
+            7 => &mut self.observe,
+            8 => &mut self.veneration,
+            9 => &mut self.name_of_the_elements,
+            10 => &mut self.name_of_the_elements_used,
+            11 => &mut self.expedience,
+            12 => &mut self.trained_perfection,
+            13 => &mut self.trained_perfection_used,
+            14 => &mut self.quick_innovation_used,
+            15 => &mut self.immaculate_mend_used,
+            16 => &mut self.careful_observation_used,
+            17 => &mut self.heart_and_soul,
+            18 => &mut self.heart_and_soul_used,
+            19 => &mut self.first_step,
+            20 => &mut self.muscle_memory,
+            21 => &mut self.final_appraisal,
+            _ => panic!("Unknown buff field"),
+        }
+    }
+
+    pub fn fields(&self) -> [u8;Buff::NUM_FIELDS] {
+        [
+            self.inner_quiet,
+            self.manipulation,
+            self.innovation,
+            self.great_strides,
+            self.waste_not,
+            self.basic_touch,
+            self.standard_touch,
+            self.observe,
+            self.veneration,
+            self.name_of_the_elements,
+            self.name_of_the_elements_used,
+            self.expedience,
+            self.trained_perfection,
+            self.trained_perfection_used,
+            self.quick_innovation_used,
+            self.immaculate_mend_used,
+            self.careful_observation_used,
+            self.heart_and_soul,
+            self.heart_and_soul_used,
+            self.first_step,
+            self.muscle_memory,
+            self.final_appraisal,
+        ]
+    }
+
+    // 1回の製作で1度だけ使えるアクションを使った数です。
+    // CPも耐久も消費しないアクションは、これが増える方向にしか遷移しません
+    pub fn num_used(&self) -> u8 {
+        self.name_of_the_elements_used + self.trained_perfection_used + self.quick_innovation_used + self.immaculate_mend_used +
+            self.careful_observation_used + self.heart_and_soul_used
+    }
+
     pub fn next_turn(&self) -> Buff {
         Buff {
             inner_quiet: self.inner_quiet,
             manipulation: self.manipulation.sub_clip(1),
+            veneration: self.veneration.sub_clip(1),
+            name_of_the_elements: self.name_of_the_elements.sub_clip(1),
+            name_of_the_elements_used: self.name_of_the_elements_used,
             innovation: self.innovation.sub_clip(1),
             great_strides: self.great_strides.sub_clip(1),
             waste_not: self.waste_not.sub_clip(1),
             basic_touch: self.basic_touch.sub_clip(1),
+            standard_touch: self.standard_touch.sub_clip(1),
             observe: self.observe.sub_clip(1),
+            expedience: self.expedience.sub_clip(1),
+            trained_perfection: self.trained_perfection,
+            trained_perfection_used: self.trained_perfection_used,
+            quick_innovation_used: self.quick_innovation_used,
+            immaculate_mend_used: self.immaculate_mend_used,
+            careful_observation_used: self.careful_observation_used,
+            heart_and_soul: self.heart_and_soul,
+            heart_and_soul_used: self.heart_and_soul_used,
+            first_step: 0,
+            muscle_memory: self.muscle_memory.sub_clip(1),
+            final_appraisal: self.final_appraisal.sub_clip(1),
         }
     }
 }
 
+// アクションの効果はルールごとに定義します。ここにはルールから使う共通の操作を置きます
 impl State {
-    fn get_required_cp( &self, a:&Action ) -> u16 {
-        match a {
-            Action::CannotAction => 0,
-            Action::BasicTouch => 18,
-            Action::StandardTouch => if self.buff.basic_touch > 0 { 18 } else { 32 },
-            Action::PrudentTouch => 25,
-            Action::FocusedTouch => 18,
-            Action::PreparatoryTouch => 40,
-            Action::ByregotsBlessing => 24,
-            Action::MastersMend => 88,
-            Action::Observe => 7,
-            Action::WasteNot => 56,
-            Action::WasteNot2 => 98,
-            Action::GreatStrides => 32,
-            Action::Innovation => 18,
-            Action::Manipulation => 96,
-        }
+    pub fn check_action(&self, setting:&Setting, a:&Action) -> bool {
+        setting.rules().check_action(self, setting, a)
     }
 
-    fn get_required_durability( &self, a:&Action ) -> u8 {
-        let d = match a {
-            Action::CannotAction => 0,
-            Action::BasicTouch => 10,
-            Action::StandardTouch => 10,
-            Action::PrudentTouch => 5,
-            Action::FocusedTouch => 10,
-            Action::PreparatoryTouch => 20,
-            Action::ByregotsBlessing => 10,
-            Action::MastersMend => 0,
-            Action::Observe => 0,
-            Action::WasteNot => 0,
-            Action::WasteNot2 => 0,
-            Action::GreatStrides => 0,
-            Action::Innovation => 0,
-            Action::Manipulation => 0,
-        };
-
-        if self.buff.waste_not > 0 { d / 2 } else { d }
+    pub fn run_action(&self, setting:&Setting, a:&Action) -> Outcomes {
+        setting.rules().run_action(self, setting, a)
     }
 
-    pub fn check_action(&self, a:&Action) -> bool {
-        if self.cp >= self.get_required_cp(&a) {
-            match a {
-                Action::ByregotsBlessing => self.buff.inner_quiet >= 1, // ビエルゴはinner_quietが1以上の時に使えます
-                Action::PrudentTouch => self.buff.waste_not == 0,       // 倹約加工は倹約が無効の時に使えます
-                Action::FocusedTouch => self.buff.observe != 0,         // 注視加工は経過観察の直後のみ使えます(実際はいつでも使えるけど成功率50%は本ツールには適していません)
-                _ => true
-            }
-        }
-        else {
-            false
-        }
+    pub fn consume_cp(&self, x:u16) -> State {
+        State { cp: self.cp - x, ..*self }
     }
 
-    // 効率に対する品質報酬
-    // こちらの記事が紹介しているcalculatorの内容を参考にしています。
-    // https://jp.finalfantasyxiv.com/lodestone/character/29523439/blog/4641394/
-    // 完全一致はしませんが、近似値として使えます。完全一致を求めるならば、データシートを作るほうが良いと思う
-    fn quality_reward(&self, setting:&Setting, efficiency : f64) -> u32 {
-        let inner_quiet : f64 = From::from(self.buff.inner_quiet);
-        let process_accuracy : f64 = From::from(setting.process_accuracy);
-        let required_process_accuracy : f64 = From::from(setting.required_process_accuracy);
-
-        let f = if inner_quiet == 0.0 { process_accuracy } else { process_accuracy + process_accuracy * ((inner_quiet-1.0) * 20.0 / 100.0) };
-        let q1 = f*35.0/100.0 + 35.0;
-        let q2 = q1 * (f + 10000.0) / (required_process_accuracy + 10000.0);
-        let q3 = q2 * 60.0 / 100.0;
-        let cond_rate = 1.0; // 高品質とか気にしないので常に1.0になります
-        let buff_rate = 1.0 + if self.buff.great_strides > 0 { 1.0 } else { 0.0 } + if self.buff.innovation > 0 { 0.5 } else { 0.0 };
+    pub fn consume_durability(&self, x:u8) -> State {
+        State { durability: self.durability.sub_clip(x), ..*self }
+    }
 
-        return ( q3 * cond_rate * efficiency * buff_rate ) as u32;
+    pub fn add_durability(&self, x:u8, setting:&Setting) -> State {
+        State { durability: min(self.durability.saturating_add(x), setting.max_durability), ..*self }
     }
 
-    fn byregots_quality_reward(&self, setting:&Setting) -> u32 {
-        self.quality_reward(setting, 1.0 + (self.buff.inner_quiet-1) as f64 * 0.2)
+    pub fn add_cp(&self, x:u16, setting:&Setting) -> State {
+        State { cp: min(self.cp + x, setting.max_cp), ..*self }
     }
 
-    fn consume_cp(&self, a:&Action) -> State {
-        State { cp: self.cp - self.get_required_cp(a), ..*self }
+    pub fn add_progress(&self, x:u16) -> State {
+        State { progress: self.progress + x, ..*self }
     }
 
-    fn consume_durability(&self, a:&Action) -> State {
-        State { durability: self.durability.sub_clip(self.get_required_durability(a)), ..*self }
+    pub fn set_veneration(&self, x:u8) -> State {
+        State { buff: Buff{ veneration: x, ..self.buff }, ..*self }
     }
 
-    fn add_durability(&self, x:u8, setting:&Setting) -> State {
-        State { durability: min(self.durability + x, setting.max_durability), ..*self }
+    pub fn set_name_of_the_elements(&self, x:u8) -> State {
+        State { buff: Buff{ name_of_the_elements: x, name_of_the_elements_used: 1, ..self.buff }, ..*self }
     }
 
-    fn set_inner_quiet(&self, x:u8) -> State {
+    pub fn set_inner_quiet(&self, x:u8) -> State {
         State { buff: Buff{ inner_quiet: x, ..self.buff }, ..*self }
     }
 
-    fn set_manipulation(&self, x:u8) -> State {
+    pub fn set_manipulation(&self, x:u8) -> State {
         State { buff: Buff{ manipulation: x, ..self.buff }, ..*self }
     }
 
-    fn set_waste_not(&self, x:u8) -> State {
+    pub fn set_waste_not(&self, x:u8) -> State {
         State { buff: Buff{ waste_not: x, ..self.buff }, ..*self }
     }
 
-    fn set_great_strides(&self, x:u8) -> State {
+    pub fn set_great_strides(&self, x:u8) -> State {
         State { buff: Buff{ great_strides: x, ..self.buff }, ..*self }
     }
 
-    fn set_innovation(&self, x:u8) -> State {
+    pub fn set_innovation(&self, x:u8) -> State {
         State { buff: Buff{ innovation: x, ..self.buff }, ..*self }
     }
 
-    fn set_basic_touch(&self) -> State {
+    pub fn set_basic_touch(&self) -> State {
         State { buff: Buff{ basic_touch: 1, ..self.buff }, ..*self }
     }
 
-    fn set_observe(&self) -> State {
-        State { buff: Buff{ observe: 1, ..self.buff }, ..*self }
+    pub fn set_standard_touch(&self) -> State {
+        State { buff: Buff{ standard_touch: 1, ..self.buff }, ..*self }
     }
 
-    fn next_turn(&self, setting:&Setting) -> State {
-        State {
-            durability: if self.buff.manipulation == 0 || self.durability == 0 { self.durability } else { min(self.durability + 5,setting.max_durability) },
-            cp: self.cp,
-            buff: self.buff.next_turn(),
-        }
+    pub fn set_observe(&self) -> State {
+        State { buff: Buff{ observe: 1, ..self.buff }, ..*self }
     }
 
-    fn run_basic_touch(&self, setting:&Setting) -> (State,u32) {
-        (self.consume_cp(&Action::BasicTouch).consume_durability(&Action::BasicTouch).next_turn(setting).set_basic_touch().set_great_strides(0), self.quality_reward(setting, 1.0))
+    pub fn set_expedience(&self) -> State {
+        State { buff: Buff{ expedience: 1, ..self.buff }, ..*self }
     }
 
-    fn run_standard_touch(&self, setting:&Setting) -> (State,u32) {
-        (self.consume_cp(&Action::StandardTouch).consume_durability(&Action::StandardTouch).next_turn(setting).set_great_strides(0), self.quality_reward(setting, 1.25))
+    pub fn set_trained_perfection(&self) -> State {
+        State { buff: Buff{ trained_perfection: 1, trained_perfection_used: 1, ..self.buff }, ..*self }
     }
 
-    fn run_prudent_touch(&self, setting:&Setting) -> (State,u32) {
-        (self.consume_cp(&Action::PrudentTouch).consume_durability(&Action::PrudentTouch).next_turn(setting).set_great_strides(0), self.quality_reward(setting,1.0))
+    pub fn clear_trained_perfection(&self) -> State {
+        State { buff: Buff{ trained_perfection: 0, ..self.buff }, ..*self }
     }
 
-    fn run_focused_touch(&self, setting:&Setting) -> (State,u32) {
-        (self.consume_cp(&Action::FocusedTouch).consume_durability(&Action::FocusedTouch).next_turn(setting).set_great_strides(0), self.quality_reward(setting,1.5))
+    pub fn set_quick_innovation_used(&self) -> State {
+        State { buff: Buff{ quick_innovation_used: 1, ..self.buff }, ..*self }
     }
 
-    fn run_preparatory_touch( &self, setting:&Setting ) -> (State,u32) {
-        (self.consume_cp(&Action::PreparatoryTouch).consume_durability(&Action::PreparatoryTouch).next_turn(setting).set_great_strides(0), self.quality_reward(setting,2.0))
+    pub fn set_immaculate_mend_used(&self) -> State {
+        State { buff: Buff{ immaculate_mend_used: 1, ..self.buff }, ..*self }
     }
 
-    fn run_byregots_blessing(&self, setting:&Setting) -> (State,u32) {
-        (self.consume_cp(&Action::ByregotsBlessing).consume_durability(&Action::ByregotsBlessing).next_turn(setting).set_inner_quiet(0).set_great_strides(0), self.byregots_quality_reward(setting))
+    pub fn add_careful_observation_used(&self) -> State {
+        State { buff: Buff{ careful_observation_used: self.buff.careful_observation_used + 1, ..self.buff }, ..*self }
     }
 
-    fn run_masters_mend(&self, setting:&Setting) -> (State,u32) {
-        (self.consume_cp(&Action::MastersMend).add_durability(30,setting).next_turn(setting),0)
+    pub fn set_heart_and_soul(&self) -> State {
+        State { buff: Buff{ heart_and_soul: 1, heart_and_soul_used: 1, ..self.buff }, ..*self }
     }
 
-    fn run_observe(&self, setting:&Setting) -> (State,u32) {
-        (self.consume_cp(&Action::Observe).next_turn(setting).set_observe(),0)
+    // 一心不乱の効果は高品質以上でない時に使った場合だけ消えます
+    pub fn consume_heart_and_soul(&self, condition:&Condition) -> State {
+        if condition.is_good() { *self } else { State { buff: Buff{ heart_and_soul: 0, ..self.buff }, ..*self } }
     }
 
-    fn run_waste_not(&self, setting:&Setting) -> (State,u32) {
-        (self.consume_cp(&Action::WasteNot).next_turn(setting).set_waste_not(4),0)
+    pub fn set_muscle_memory(&self, x:u8) -> State {
+        State { buff: Buff{ muscle_memory: x, ..self.buff }, ..*self }
     }
 
-    fn run_waste_not_2(&self, setting:&Setting) -> (State,u32) {
-        (self.consume_cp(&Action::WasteNot2).next_turn(setting).set_waste_not(8),0)
+    pub fn set_final_appraisal(&self, x:u8) -> State {
+        State { buff: Buff{ final_appraisal: x, ..self.buff }, ..*self }
     }
 
-    fn run_great_strides(&self, setting:&Setting) -> (State,u32) {
-        (self.consume_cp(&Action::GreatStrides).next_turn(setting).set_great_strides(3),0)
+    // 行動前に最終確認が有効だった場合、完成するはずの工数は完成の1単位手前で止まり、最終確認の効果が消えます
+    pub fn hold_progress(&self, before:&State, setting:&Setting) -> State {
+        let required = setting.required_progress();
+        if before.buff.final_appraisal > 0 && required > 0 && self.progress >= required {
+            State { progress: required - 1, buff: Buff{ final_appraisal: 0, ..self.buff }, ..*self }
+        }
+        else {
+            *self
+        }
     }
 
-    fn run_innovation(&self, setting:&Setting) -> (State,u32) {
-        (self.consume_cp(&Action::Innovation).next_turn(setting).set_innovation(4),0)
+    pub fn next_turn(&self, setting:&Setting) -> State {
+        // 次の工程の状態は確率的に決まるので、ここでは変えずに呼び出し側で展開します
+        State {
+            durability: if self.buff.manipulation == 0 || self.durability == 0 { self.durability } else { min(self.durability + 5,setting.max_durability) },
+            cp: self.cp,
+            progress: self.progress,
+            condition: self.condition,
+            buff: self.buff.next_turn(),
+        }
     }
+}
 
-    fn run_manipulation(&self, setting:&Setting) -> (State,u32) {
-        (self.consume_cp(&Action::Manipulation).set_manipulation(0).next_turn(setting).set_manipulation(8),0)
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 継続が有効な場合は倹約、マニピュレーション、イノベーション、グレートストライドだけが2工程延長されます
+    #[test]
+    fn sustain_extends_listed_buffs() {
+        let s = State::example( 400, 40, Buff::default() );
+        for (sustain,extra) in [(false,0),(true,2)] {
+            let setting = Setting { sustain, ..Setting::example( Rules::Patch5, 400, 40 ) };
+            let run = |a:Action| s.run_action( &setting, &a ).success.0.buff;
+            assert_eq!( run(Action::WasteNot).waste_not, 4 + extra );
+            assert_eq!( run(Action::WasteNot2).waste_not, 8 + extra );
+            assert_eq!( run(Action::Manipulation).manipulation, 8 + extra );
+            assert_eq!( run(Action::Innovation).innovation, 4 + extra );
+            assert_eq!( run(Action::GreatStrides).great_strides, 3 + extra );
+            assert_eq!( run(Action::Veneration).veneration, 4 );
+            assert_eq!( run(Action::FinalAppraisal).final_appraisal, 5 );
+        }
     }
 
-    pub fn run_action( &self, setting:&Setting, a:&Action ) -> (State,u32) {
-        match a {
-            Action::CannotAction => panic!("Cannot run action"),
-            Action::BasicTouch => self.run_basic_touch(setting),
-            Action::StandardTouch => self.run_standard_touch(setting),
-            Action::PrudentTouch => self.run_prudent_touch(setting),
-            Action::FocusedTouch => self.run_focused_touch(setting),
-            Action::PreparatoryTouch => self.run_preparatory_touch(setting),
-            Action::ByregotsBlessing => self.run_byregots_blessing(setting),
-            Action::MastersMend => self.run_masters_mend(setting),
-            Action::Observe => self.run_observe(setting),
-            Action::WasteNot => self.run_waste_not(setting),
-            Action::WasteNot2 => self.run_waste_not_2(setting),
-            Action::GreatStrides => self.run_great_strides(setting),
-            Action::Innovation => self.run_innovation(setting),
-            Action::Manipulation => self.run_manipulation(setting),
+    // 確信と確信作業は最初の工程でのみ使え、使った後は最初の工程ではなくなります
+    #[test]
+    fn openers_only_on_first_step() {
+        let setting = Setting { crafter_level:90, difficulty:3000, ..Setting::example( Rules::Patch6, 400, 40 ) };
+        let first = State::example( 400, 40, Buff { first_step:1, ..Default::default() } );
+        let later = State::example( 400, 40, Buff::default() );
+
+        for a in [Action::Reflect, Action::MuscleMemory] {
+            assert!( first.check_action( &setting, &a ) );
+            assert!( !later.check_action( &setting, &a ) );
+            assert_eq!( first.run_action( &setting, &a ).success.0.buff.first_step, 0 );
         }
+
+        let (ns,q) = first.run_action( &setting, &Action::Reflect ).success;
+        assert!( ns.buff.inner_quiet == 2 && q > 0 );
+        let (ns,_) = first.run_action( &setting, &Action::MuscleMemory ).success;
+        assert!( ns.progress > 0 && ns.buff.muscle_memory > 0 );
+
+        // 最初の工程で他のアクションを使うと、もう使えません
+        let (ns,_) = first.run_action( &setting, &Action::BasicTouch ).success;
+        assert!( !ns.check_action( &setting, &Action::Reflect ) );
+    }
+
+    // 耐久の回復は最大耐久で止まります。完璧な修復は最大耐久の分を回復するので、耐久が残っていてもu8を越えません
+    #[test]
+    fn durability_restore_clamped() {
+        let setting = Setting { crafter_level:100, ..Setting::example( Rules::Patch7, 400, 200 ) };
+        let s = State::example( 400, 190, Buff::default() );
+        assert_eq!( s.add_durability( 80, &setting ).durability, 200 );
+        assert!( s.check_action( &setting, &Action::ImmaculateMend ) );
+        assert_eq!( s.run_action( &setting, &Action::ImmaculateMend ).success.0.durability, 200 );
+    }
+
+    // 最終確認の効果中は、完成する工数に達しても完成の1単位手前で止まり、最終確認の効果が消えます
+    #[test]
+    fn final_appraisal_holds_progress() {
+        let setting = Setting { crafter_level:90, difficulty:3000, ..Setting::example( Rules::Patch6, 400, 40 ) };
+        let required = setting.required_progress();
+        let s = State { progress: required - 1, ..State::example( 400, 40, Buff::default() ) };
+
+        let (ns,_) = s.run_action( &setting, &Action::BasicSynthesis ).success;
+        assert!( ns.progress >= required );
+
+        let (held,_) = s.run_action( &setting, &Action::FinalAppraisal ).success;
+        assert!( held.buff.final_appraisal > 0 && setting.rules().keeps_condition( &Action::FinalAppraisal ) );
+        let (ns,_) = held.run_action( &setting, &Action::BasicSynthesis ).success;
+        assert_eq!( (ns.progress, ns.buff.final_appraisal), (required - 1, 0) );
+
+        // 工数を追跡しない場合は使いません
+        let setting = Setting { difficulty:0, ..setting };
+        assert!( !s.check_action( &setting, &Action::FinalAppraisal ) );
     }
 }
@@ -1,23 +1,49 @@
 
 mod logic;
+mod rules;
+mod formula;
 mod statespace;
 mod table;
 mod repl;
+mod cache;
+mod lazy;
 
-use logic::Setting;
-use table::*;
+use logic::{Setting,Objective};
+use rules::Rules;
+use formula::QualityFormula;
 use repl::*;
+use cache::load_or_calc_table;
 
 fn main() {
+    let rules = Rules::Patch5;
     let setting = Setting {
         max_durability: 55,
         max_cp: 657,
         sustain: false,
+        specialist: false,
+        conditions: false,
         process_accuracy: 2910,
         required_process_accuracy: 2540,
+        craftsmanship: 2763,
+        required_craftsmanship: 2480,
+        difficulty: 0,
+        max_quality: 0,
+        initial_quality: 0,
+        collectability_tiers: vec![],
+        objective: Objective::Quality,
+        reserve_cp: 0,
+        reserve_durability: 0,
+        crafter_level: rules.get().max_level(),
+        quality_formula: QualityFormula::Approximate,
+        rules,
     };
 
-    let (_,ta) = calc_table( &setting );
-
-    repl( &setting, &ta );
+    // 最初の設定の表が計算できない場合は終了します
+    match load_or_calc_table( &setting ) {
+        Ok((tv,ta)) => repl( setting, tv, ta ),
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        },
+    }
 }
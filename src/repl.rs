@@ -1,6 +1,10 @@
 
-use super::logic::{State,Buff,Action,Setting};
+use super::logic::{State,Buff,Condition,Action,Setting,Objective,CollectabilityTier};
 use super::table::*;
+use super::cache::{load_or_calc_table,load_or_extend_table};
+use super::lazy::{LazySolver,QualitySolver,from_utility};
+use super::rules::{Rules,DataRules};
+use super::formula::{QualityFormula,RecipeLevel,hq_percent};
 use rustyline::Editor;
 use rustyline::error::ReadlineError;
 
@@ -11,7 +15,22 @@ enum CmdLine {
     Empty,
     Verbose,
     Simple,
-    Eval(State),
+    Eval(State,u32),
+    Lazy(State,u32),
+    Alternatives(State,u32,usize),
+    Rules(Option<Rules>),
+    LoadRules(String),
+    Formula(Option<QualityFormula>),
+    Objective(Option<Objective>),
+    Reserve(Option<(u16,u8)>),
+    Level(Option<u8>),
+    MaxCp(Option<u16>),
+    Conditions(Option<bool>),
+    Specialist(Option<bool>),
+    Sustain(Option<bool>),
+    Difficulty(Option<u32>),
+    MaxQuality(Option<u32>),
+    Tiers(Option<Vec<CollectabilityTier>>),
 }
 
 struct ReplContext
@@ -19,6 +38,50 @@ struct ReplContext
     verbose : bool,
 }
 
+// REPLで参照する表です。評価値の表は最大CPを上げる時に表を広げるためと、候補の比較のために持ちます
+struct Tables
+{
+    values : Table<u32>,
+    actions : ActionTable,
+    setting : Setting,  // 表を計算した設定(Setting::table_setting)
+}
+
+impl Tables {
+    // 表が大きすぎて計算できない場合は今の表のままにします。
+    // 品質に上限がある間は表を使わずに遅延評価で答えるので、計算も保存もしません。上限を外した時に計算し直します
+    fn recalc(&mut self, setting:&Setting) -> Result<(),String> {
+        let table_setting = setting.table_setting();
+        if setting.quality_cap().is_some() || self.setting == table_setting {
+            return Ok(());
+        }
+        (self.values, self.actions) = load_or_calc_table( &table_setting )?;
+        self.setting = table_setting;
+        Ok(())
+    }
+
+    // 最大CPを上げた設定に合わせて、今の表に増えたCPの領域を足します。最大CP以外も今の表と違う場合は計算し直します
+    fn extend(&mut self, setting:&Setting) -> Result<(),String> {
+        let table_setting = setting.table_setting();
+        if setting.quality_cap().is_some() {
+            return Ok(());
+        }
+        if self.setting != (Setting { max_cp:self.setting.max_cp, ..table_setting.clone() }) {
+            return self.recalc( setting );
+        }
+        (self.values, self.actions) = load_or_extend_table( &table_setting, &self.values, &self.actions )?;
+        self.setting = table_setting;
+        Ok(())
+    }
+}
+
+// 新しい設定で表を計算し直します。計算できない場合はエラーを表示して、設定も表も元のままにします
+fn update( setting:&mut Setting, tables:&mut Tables, new:Setting, calc:fn(&mut Tables,&Setting) -> Result<(),String> ) {
+    match calc( tables, &new ) {
+        Ok(()) => *setting = new,
+        Err(e) => println!("{}", e),
+    }
+}
+
 impl Action {
     fn translate_ja(&self) -> &'static str {
         match *self {
@@ -36,11 +99,187 @@ impl Action {
             Action::GreatStrides => "グレートストライド",
             Action::Innovation => "イノベーション",
             Action::Manipulation => "マニピュレーション",
+            Action::InnerQuiet => "インナークワイエット",
+            Action::PreciseTouch => "集中加工",
+            Action::TricksOfTheTrade => "秘訣",
+            Action::HastyTouch => "ヘイスティタッチ",
+            Action::PatientTouch => "専心加工",
+            Action::BasicSynthesis => "作業",
+            Action::CarefulSynthesis => "模範作業",
+            Action::RapidSynthesis => "突貫作業",
+            Action::IntensiveSynthesis => "集中作業",
+            Action::Groundwork => "下地作業",
+            Action::DelicateSynthesis => "精密作業",
+            Action::BrandOfTheElements => "ブランド・オブ・エレメント",
+            Action::Veneration => "ヴェネレーション",
+            Action::NameOfTheElements => "ネーム・オブ・エレメント",
+            Action::AdvancedTouch => "上級加工",
+            Action::TrainedFinesse => "匠の絶技",
+            Action::PrudentSynthesis => "倹約作業",
+            Action::RefinedTouch => "洗練加工",
+            Action::DaringTouch => "デアリングタッチ",
+            Action::QuickInnovation => "クイックイノベーション",
+            Action::TrainedPerfection => "匠の神業",
+            Action::ImmaculateMend => "パーフェクトメンド",
+            Action::CarefulObservation => "設計変更",
+            Action::HeartAndSoul => "一心不乱",
+            Action::Reflect => "確信",
+            Action::MuscleMemory => "確信作業",
+            Action::TrainedEye => "匠の早業",
+            Action::FinalAppraisal => "最終確認",
+            Action::Data(_) => "データ定義のアクション",
         }
     }
 }
 
-fn parse_eval( v:&[&str] ) -> Result<CmdLine,&'static str> {
+impl Condition {
+    fn translate_ja(&self) -> &'static str {
+        match *self {
+            Condition::Normal => "通常",
+            Condition::Good => "高品質",
+            Condition::Excellent => "最高品質",
+            Condition::Poor => "低品質",
+        }
+    }
+}
+
+fn parse_condition( x:&str ) -> Result<Condition,&'static str> {
+    match x {
+        "normal" | "n" => Ok(Condition::Normal),
+        "good" | "g" => Ok(Condition::Good),
+        "excellent" | "e" => Ok(Condition::Excellent),
+        "poor" | "p" => Ok(Condition::Poor),
+        _ => Err("cannot parse condition(normal,good,excellent,poor)"),
+    }
+}
+
+fn parse_rules( v:&[&str] ) -> Result<CmdLine,&'static str> {
+    match v {
+        [] => Ok(CmdLine::Rules(None)),
+        ["5" | "5.5"] => Ok(CmdLine::Rules(Some(Rules::Patch5))),
+        ["6" | "6.x"] => Ok(CmdLine::Rules(Some(Rules::Patch6))),
+        ["7" | "7.x"] => Ok(CmdLine::Rules(Some(Rules::Patch7))),
+        [x] if x.ends_with(".toml") => Ok(CmdLine::LoadRules(x.to_string())),
+        _ => Err("cannot parse rules(5.5,6.x,7.x,action definition file)"),
+    }
+}
+
+fn parse_formula( v:&[&str] ) -> Result<CmdLine,&'static str> {
+    match v {
+        [] => Ok(CmdLine::Formula(None)),
+        ["approx"] => Ok(CmdLine::Formula(Some(QualityFormula::Approximate))),
+        [x] => match x.parse::<u16>().ok().and_then(RecipeLevel::find) {
+            Some(r) => Ok(CmdLine::Formula(Some(QualityFormula::Exact(r)))),
+            None => Err("unknown recipe level(supported: 560 570 580 590 610 620 640 690 700 710 720, or use approx)"),
+        },
+        _ => Err("cannot parse formula(approx or recipe level)"),
+    }
+}
+
+fn parse_objective( v:&[&str] ) -> Result<CmdLine,&'static str> {
+    match v {
+        [] => Ok(CmdLine::Objective(None)),
+        ["quality"] => Ok(CmdLine::Objective(Some(Objective::Quality))),
+        ["hq"] => Ok(CmdLine::Objective(Some(Objective::HqPercent))),
+        _ => Err("cannot parse objective(quality,hq)"),
+    }
+}
+
+fn parse_level( v:&[&str] ) -> Result<CmdLine,&'static str> {
+    match v {
+        [] => Ok(CmdLine::Level(None)),
+        [x] => match x.parse::<u8>() {
+            Ok(x) if (1..=100).contains(&x) => Ok(CmdLine::Level(Some(x))),
+            _ => Err("cannot parse level(1-100)"),
+        },
+        _ => Err("cannot parse level(1-100)"),
+    }
+}
+
+fn parse_max_cp( v:&[&str] ) -> Result<CmdLine,&'static str> {
+    match v {
+        [] => Ok(CmdLine::MaxCp(None)),
+        [x] => match x.parse::<u16>() {
+            Ok(x) => Ok(CmdLine::MaxCp(Some(x))),
+            _ => Err("cannot parse max CP"),
+        },
+        _ => Err("cannot parse max CP"),
+    }
+}
+
+fn parse_conditions( v:&[&str] ) -> Result<CmdLine,&'static str> {
+    match v {
+        [] => Ok(CmdLine::Conditions(None)),
+        ["on"] => Ok(CmdLine::Conditions(Some(true))),
+        ["off"] => Ok(CmdLine::Conditions(Some(false))),
+        _ => Err("conditions [on|off]"),
+    }
+}
+
+fn parse_specialist( v:&[&str] ) -> Result<CmdLine,&'static str> {
+    match v {
+        [] => Ok(CmdLine::Specialist(None)),
+        ["on"] => Ok(CmdLine::Specialist(Some(true))),
+        ["off"] => Ok(CmdLine::Specialist(Some(false))),
+        _ => Err("specialist [on|off]"),
+    }
+}
+
+fn parse_sustain( v:&[&str] ) -> Result<CmdLine,&'static str> {
+    match v {
+        [] => Ok(CmdLine::Sustain(None)),
+        ["on"] => Ok(CmdLine::Sustain(Some(true))),
+        ["off"] => Ok(CmdLine::Sustain(Some(false))),
+        _ => Err("sustain [on|off]"),
+    }
+}
+
+fn parse_difficulty( v:&[&str] ) -> Result<CmdLine,&'static str> {
+    match v {
+        [] => Ok(CmdLine::Difficulty(None)),
+        [x] => match x.parse::<u32>() {
+            Ok(x) => Ok(CmdLine::Difficulty(Some(x))),
+            _ => Err("cannot parse difficulty"),
+        },
+        _ => Err("cannot parse difficulty"),
+    }
+}
+
+fn parse_max_quality( v:&[&str] ) -> Result<CmdLine,&'static str> {
+    match v {
+        [] => Ok(CmdLine::MaxQuality(None)),
+        [x] => match x.parse::<u32>() {
+            Ok(x) => Ok(CmdLine::MaxQuality(Some(x))),
+            _ => Err("cannot parse max quality"),
+        },
+        _ => Err("cannot parse max quality"),
+    }
+}
+
+// 段階は[収集価値]:[報酬]で並べます。offで段階を無くします
+fn parse_tiers( v:&[&str] ) -> Result<CmdLine,&'static str> {
+    match v {
+        [] => Ok(CmdLine::Tiers(None)),
+        ["off"] => Ok(CmdLine::Tiers(Some(vec![]))),
+        _ => v.iter().map(|x| match x.split_once(':').map(|(c,r)| (c.parse::<u32>(), r.parse::<u32>())) {
+            Some((Ok(collectability), Ok(reward))) if collectability > 0 => Ok(CollectabilityTier { collectability, reward }),
+            _ => Err("cannot parse tiers([collectability]:[reward] ... or off)"),
+        }).collect::<Result<Vec<_>,_>>().map(|t| CmdLine::Tiers(Some(t))),
+    }
+}
+
+fn parse_reserve( v:&[&str] ) -> Result<CmdLine,&'static str> {
+    match v {
+        [] => Ok(CmdLine::Reserve(None)),
+        [cp, durability] => match (cp.parse::<u16>(), durability.parse::<u8>()) {
+            (Ok(cp), Ok(durability)) => Ok(CmdLine::Reserve(Some((cp,durability)))),
+            _ => Err("cannot parse reserve(CP and durability)"),
+        },
+        _ => Err("cannot parse reserve(CP and durability)"),
+    }
+}
+
+fn parse_eval( setting:&Setting, v:&[&str] ) -> Result<(State,u32),&'static str> {
 
     if v.len() < 2 {
         Err("not enough arguments")
@@ -57,23 +296,88 @@ fn parse_eval( v:&[&str] ) -> Result<CmdLine,&'static str> {
         };
 
         // 耐久は5の倍数切り上げします。
-        let durability = ((d+4) / 5 * 5) as u8;
+        let durability = d.div_ceil(5) * 5;
+
+        // 3つ目以降の引数はkey=value形式のオプションです。
+        let mut inner_quiet = setting.rules().max_inner_quiet();
+        let mut condition = Condition::Normal;
+        let mut progress = 0;
+        let mut first_step = false;
+        let mut quality = 0;
+        for opt in &v[2..] {
+            match opt.split_once('=') {
+                Some(("iq",x)) => inner_quiet = match x.parse::<u8>() {
+                    Ok(x) if x <= setting.rules().max_inner_quiet() => x,
+                    _ => return Err("cannot parse inner quiet"),
+                },
+                Some(("cond",x)) => condition = match parse_condition(x)? {
+                    c if c != Condition::Normal && !setting.conditions => return Err("conditions are off(use conditions on)"),
+                    c => c,
+                },
+                Some(("p",x)) => progress = match x.parse::<u32>() {
+                    Ok(x) => setting.progress_units(x),
+                    _ => return Err("cannot parse progress"),
+                },
+                Some(("q",x)) => quality = match x.parse::<u32>() {
+                    Ok(x) => x,
+                    _ => return Err("cannot parse quality"),
+                },
+                None if *opt == "first" && !setting.tracks_progress() => return Err("first needs progress tracked(use difficulty)"),
+                None if *opt == "first" => first_step = true,
+                _ => return Err("unknown option"),
+            }
+        }
+
+        // 最初の工程からの場合はまだインナークワイエットのスタックがありません
+        let buff = if first_step { Buff { first_step:1, ..Default::default() } } else { Buff { inner_quiet, ..Default::default() } };
+
+        Ok( (State { cp, durability, progress, condition, buff }, quality) )
+    }
+}
 
-        Ok( CmdLine::Eval( State { cp:cp, durability:durability, buff: Buff { inner_quiet:11, manipulation:0, innovation:0, great_strides:0, waste_not:0, basic_touch:0, observe:0 }} ) )
+// 候補の数はk=[数]で指定します。それ以外はevalと同じです
+fn parse_alternatives( setting:&Setting, v:&[&str] ) -> Result<CmdLine,&'static str> {
+    let mut k = 3;
+    let mut args = Vec::new();
+    for x in v {
+        match x.strip_prefix("k=") {
+            Some(x) => k = match x.parse::<usize>() {
+                Ok(x) if x > 0 => x,
+                _ => return Err("cannot parse number of alternatives"),
+            },
+            None => args.push(*x),
+        }
     }
+
+    let (s,q) = parse_eval(setting, &args)?;
+    Ok(CmdLine::Alternatives(s,q,k))
 }
 
 fn is_all_numeric(s:&str) -> bool {
     s.chars().all(|c| c.is_ascii_digit())
 }
 
-fn parse_cmdline( line:&str ) -> Result<CmdLine,&'static str> {
+fn parse_cmdline( setting:&Setting, line:&str ) -> Result<CmdLine,&'static str> {
     let v: Vec<&str> = line.split_whitespace().collect();
 
-    if v.len() > 0 {
+    if !v.is_empty() {
         match v[0] {
-            x if is_all_numeric(x) => parse_eval(&v[0..]),
-            "eval" => parse_eval(&v[1..]),
+            x if is_all_numeric(x) => parse_eval(setting, &v[0..]).map(|(s,q)| CmdLine::Eval(s,q)),
+            "eval" => parse_eval(setting, &v[1..]).map(|(s,q)| CmdLine::Eval(s,q)),
+            "lazy" => parse_eval(setting, &v[1..]).map(|(s,q)| CmdLine::Lazy(s,q)),
+            "alt" => parse_alternatives(setting, &v[1..]),
+            "rules" => parse_rules(&v[1..]),
+            "formula" => parse_formula(&v[1..]),
+            "objective" => parse_objective(&v[1..]),
+            "reserve" => parse_reserve(&v[1..]),
+            "level" => parse_level(&v[1..]),
+            "maxcp" => parse_max_cp(&v[1..]),
+            "conditions" => parse_conditions(&v[1..]),
+            "specialist" => parse_specialist(&v[1..]),
+            "sustain" => parse_sustain(&v[1..]),
+            "difficulty" => parse_difficulty(&v[1..]),
+            "maxquality" => parse_max_quality(&v[1..]),
+            "tiers" => parse_tiers(&v[1..]),
             "?" => Ok(CmdLine::Help),
             "h" => Ok(CmdLine::Help),
             "v" => Ok(CmdLine::Verbose),
@@ -91,66 +395,483 @@ fn parse_cmdline( line:&str ) -> Result<CmdLine,&'static str> {
     }
 }
 
-fn print_series( ctx:&ReplContext, setting:&Setting, ta:&Table<Action>, initial_state:&State ) {
+// データファイルのルールではアクションの名前もファイルから取ります
+fn action_name( setting:&Setting, a:&Action ) -> &'static str {
+    setting.rules().action_name(a).unwrap_or(a.translate_ja())
+}
+
+fn print_out_of_bound( setting:&Setting ) {
+    println!("Out of bound(0<=cp<={} && 5<=durability<={} && durability%5==0)", setting.max_cp, setting.max_durability );
+}
+
+// 最初の状態でだけ決めたアクションを使い、その後は表の手順を続けます
+struct FirstAction<'a> {
+    state : State,
+    action : Action,
+    rest : &'a dyn Policy,
+}
+
+impl Policy for FirstAction<'_> {
+    fn contains(&self, s:&State) -> bool {
+        self.rest.contains(s)
+    }
+
+    fn is_completed(&self, s:&State) -> bool {
+        self.rest.is_completed(s)
+    }
+
+    fn action(&self, s:&State) -> Action {
+        if *s == self.state { self.action } else { self.rest.action(s) }
+    }
+
+    fn action_at(&self, s:&State, quality:u32) -> Action {
+        if *s == self.state { self.action } else { self.rest.action_at(s, quality) }
+    }
+}
+
+fn print_series( ctx:&ReplContext, setting:&Setting, ta:&dyn Policy, initial_state:&State ) {
 
     if !ta.contains(initial_state) {
-        println!("Out of bound(0<=cp<={} && 5<=durability<={} && durability%5==0)", setting.max_cp, setting.max_durability );
+        print_out_of_bound( setting );
         return;
     }
 
+    if setting.sustain {
+        println!("(倹約、マニピュレーション、イノベーション、グレートストライドの効果時間が2工程延長されています)");
+    }
+
     let mut s = *initial_state;
     let mut sum_q = 0;
+    let initial_quality = setting.initial_quality;
+    let cap = setting.quality_cap();
+
+    while !ta.is_completed(&s) && ta.action_at(&s, sum_q) != Action::CannotAction {
+        // 品質が上限に達したら、工数を追跡していない限りそこで終わります(残すCPと耐久があれば)
+        if cap.is_some_and(|m| sum_q >= m) && !setting.tracks_progress() && setting.can_finish(&s) {
+            break;
+        }
 
-    while ta[s] != Action::CannotAction {
-        let (ns,q) = s.run_action(&setting, &ta[s]);
+        // 成功率のあるアクションは起こりやすい方の結果が続くと仮定して表示します
+        let action = ta.action_at(&s, sum_q);
+        let outcomes = s.run_action(setting, &action);
+        let (ns,q) = outcomes.most_likely();
         sum_q += q;
 
+        let name = if outcomes.success_rate < 100 {
+            format!("{}(成功率{}%)", action_name(setting, &action), outcomes.success_rate)
+        }
+        else {
+            action_name(setting, &action).to_string()
+        };
+
         if ctx.verbose {
-            println!("耐久:{:2} CP:{:3} マニピュ:{} イノベ:{} グレスト:{} 倹約:{} 加工:{} 経過観察:{} 品質:{:5}(+{:5}) {:10}",
+            println!("耐久:{:2} CP:{:3} 工数:{:3} 状態:{:4} IQ:{:2} マニピュ:{} イノベ:{} グレスト:{} 倹約:{} 加工:{} 経過観察:{} 品質:{:5}(+{:5}) {:10}",
                 s.durability,
                 s.cp,
+                s.progress,
+                s.condition.translate_ja(),
+                s.buff.inner_quiet,
                 s.buff.manipulation,
                 s.buff.innovation,
                 s.buff.great_strides,
                 s.buff.waste_not,
                 s.buff.basic_touch,
                 s.buff.observe,
-                sum_q,
+                initial_quality + sum_q,
                 q,
-                ta[s].translate_ja() );
+                name );
         }
         else {
-            println!("{}", ta[s].translate_ja());
+            println!("{}", name);
         }
 
-        s = ns;
+        // 状態は一番起こりやすいものが続くと仮定して表示します
+        s = State { condition: s.condition.most_likely_next(setting, &action), ..ns };
     }
 
     if !ctx.verbose {
         println!("(+{})", sum_q);
     }
 
-    println!("");
+    // 開始品質がある場合は最終的な品質も、最高品質が分かる場合はHQ率も、収集品の段階がある場合は届く段階も表示します
+    let quality = initial_quality + sum_q;
+    if setting.collects() {
+        println!("品質: {} -> {} (収集価値: {} 報酬: {})", initial_quality, quality, quality / 10, setting.tier_reward(quality));
+    }
+    else if setting.max_quality > 0 {
+        println!("品質: {} -> {} (HQ率: {}% -> {}%)", initial_quality, quality, hq_percent(initial_quality, setting.max_quality), hq_percent(quality, setting.max_quality));
+    }
+    else if initial_quality > 0 {
+        println!("品質: {} -> {}", initial_quality, quality);
+    }
+
+    if cap.is_some_and(|m| sum_q >= m) {
+        println!("品質が上限に達しました");
+    }
+
+    if setting.tracks_progress() && !ta.is_completed(&s) {
+        println!("工数が足りず完成できません");
+    }
+    else if !ta.is_completed(&s) && !setting.can_finish(&s) {
+        println!("仕上げの作業に残すCPと耐久が足りません(CP:{} 耐久:{})", setting.reserve_cp, setting.reserve_durability);
+    }
+
+    println!();
 }
 
-fn print_help() {
+// インナークワイエットの上限はルールごとに違うので、今のルールの値を表示します
+fn print_help( setting:&Setting ) {
     println!("Usage:");
-    println!("  [CP] [durability]       print tactics");
-    println!("  eval [CP] [durability]  print tactics(same as above)");
-    println!("  v, verbose              verbose mode" );
-    println!("  s, simple               simple mode" );
-    println!("  ?, h, help              print help" );
-    println!("  exit, quit              exit command" );
+    println!("  [CP] [durability] [options]       print tactics");
+    println!("  eval [CP] [durability] [options]  print tactics(same as above)");
+    println!("    {:<32}inner quiet stacks(default max)", format!("iq=[0-{}]", setting.rules().max_inner_quiet()));
+    println!("    cond=[normal|good|excellent|poor] current condition(default normal)");
+    println!("    p=[progress]                    current progress(default 0)");
+    println!("    first                           start from the first step(opener actions)");
+    println!("    q=[quality]                     starting quality(default 0)");
+    println!("  alt [CP] [durability] [options]   list alternative first actions and the quality each one loses");
+    println!("    k=[count]                       number of alternatives(default 3)");
+    println!("  lazy [CP] [durability] [options]  print tactics evaluating only the states reachable from the query(no table)");
+    println!("  rules [5.5|6.x|7.x|file.toml]     print or change game version, or load action definitions(recalculates table)" );
+    println!("  formula [approx|recipe level]     print or change quality formula(recalculates table)" );
+    println!("  objective [quality|hq]            print or change what to maximize(hq needs max quality)" );
+    println!("  reserve [CP] [durability]         print or change CP and durability left for the finishing synthesis(recalculates table)" );
+    println!("  level [1-100]                     print or change crafter level(learned actions and traits, recalculates table)" );
+    println!("  maxcp [CP]                        print or change max CP(raising computes only the added CP if Tricks is unusable)" );
+    println!("  conditions [on|off]               print or change whether conditions are modeled(on makes the table 4x larger, recalculates table)" );
+    println!("  specialist [on|off]               print or change whether specialist actions are used(recalculates table)" );
+    println!("  sustain [on|off]                  print or change whether Waste Not, Manipulation, Innovation and Great Strides last 2 more steps(recalculates table)" );
+    println!("  difficulty [progress]             print or change the recipe difficulty(0 does not track progress, recalculates table)" );
+    println!("  maxquality [quality]              print or change the recipe max quality(0 for no cap, a cap uses lazy evaluation instead of the table)" );
+    println!("  tiers [collectability:reward ...|off] print or change collectability tiers(tiers use lazy evaluation instead of the table)" );
+    println!("  v, verbose                        verbose mode" );
+    println!("  s, simple                         simple mode" );
+    println!("  ?, h, help                        print help" );
+    println!("  exit, quit                        exit command" );
+}
+
+// ルールを切り替えて表を計算し直します。クラフターのレベルはそのパッチのレベルの上限にします。
+// レシピレベルの計算式を使えないルールに切り替える時は近似式に戻します
+fn change_rules( setting:&mut Setting, tables:&mut Tables, rules:Rules ) {
+    if setting.rules != rules {
+        let crafter_level = rules.get().max_level();
+        let quality_formula = if rules.get().exact_formula() { setting.quality_formula } else { QualityFormula::Approximate };
+        update( setting, tables, Setting { rules, crafter_level, quality_formula, ..setting.clone() }, Tables::recalc );
+    }
+    println!("rules: {}", setting.rules().name());
+    print_level( setting );
+    print_formula( setting );
+}
+
+// アクションの定義ファイルを読み込んでルールを切り替えます
+fn load_rules( setting:&mut Setting, tables:&mut Tables, path:&str ) {
+    match DataRules::load(path) {
+        Ok(r) => {
+            for a in r.actions() {
+                println!("  {} ({})", a.name_ja, a.name_en);
+            }
+            change_rules( setting, tables, Rules::Data(r) );
+        },
+        Err(e) => println!("{}", e),
+    }
+}
+
+fn print_formula( setting:&Setting ) {
+    match setting.quality_formula {
+        QualityFormula::Approximate => println!("formula: approx"),
+        QualityFormula::Exact(r) => println!("formula: recipe level {}", r.recipe_level),
+    }
+}
+
+// 計算式を切り替えて表を計算し直します
+fn change_formula( setting:&mut Setting, tables:&mut Tables, formula:QualityFormula ) {
+    if formula != QualityFormula::Approximate && !setting.rules().exact_formula() {
+        println!("recipe level formula is not available in {} rules", setting.rules().name());
+    }
+    else if setting.quality_formula != formula {
+        update( setting, tables, Setting { quality_formula:formula, ..setting.clone() }, Tables::recalc );
+    }
+    print_formula( setting );
+}
+
+fn print_objective( setting:&Setting ) {
+    match setting.objective {
+        Objective::Quality => println!("objective: quality"),
+        Objective::HqPercent => println!("objective: hq"),
+    }
+}
+
+// 最大化するものを切り替えて表を計算し直します。HQ率は最高品質が分からないと計算できません
+fn change_objective( setting:&mut Setting, tables:&mut Tables, objective:Objective ) {
+    if objective == Objective::HqPercent && setting.max_quality == 0 {
+        println!("objective hq needs max quality(use maxquality)");
+    }
+    else if setting.objective != objective {
+        update( setting, tables, Setting { objective, ..setting.clone() }, Tables::recalc );
+    }
+    print_objective( setting );
+}
+
+fn print_level( setting:&Setting ) {
+    println!("level: {}", setting.crafter_level);
+}
+
+// クラフターのレベルを変えて表を計算し直します。使えるアクションと特性による効率が変わります
+fn change_level( setting:&mut Setting, tables:&mut Tables, level:u8 ) {
+    if setting.crafter_level != level {
+        update( setting, tables, Setting { crafter_level:level, ..setting.clone() }, Tables::recalc );
+    }
+    print_level( setting );
+}
+
+fn print_max_cp( setting:&Setting ) {
+    println!("max CP: {}", setting.max_cp);
+}
+
+// 最大CPを変えます。上げる場合は今の表を広げ(秘訣を使える場合は最初から計算します)、下げる場合は計算し直します
+fn change_max_cp( setting:&mut Setting, tables:&mut Tables, max_cp:u16 ) {
+    if setting.max_cp < max_cp {
+        update( setting, tables, Setting { max_cp, ..setting.clone() }, Tables::extend );
+    }
+    else if setting.max_cp > max_cp {
+        update( setting, tables, Setting { max_cp, ..setting.clone() }, Tables::recalc );
+    }
+    print_max_cp( setting );
+}
+
+fn print_conditions( setting:&Setting ) {
+    println!("conditions: {}", if setting.conditions { "on" } else { "off" });
+}
+
+// 状態(コンディション)を考えるかを切り替えて表を計算し直します。考える場合は表が4倍になります
+fn change_conditions( setting:&mut Setting, tables:&mut Tables, conditions:bool ) {
+    if setting.conditions != conditions {
+        update( setting, tables, Setting { conditions, ..setting.clone() }, Tables::recalc );
+    }
+    print_conditions( setting );
+}
+
+fn print_specialist( setting:&Setting ) {
+    println!("specialist: {}", if setting.specialist { "on" } else { "off" });
+}
+
+// マイスター専用のアクションを使うかを切り替えて表を計算し直します。1回の製作で1度だけのバフが増えるので表は大きくなります
+fn change_specialist( setting:&mut Setting, tables:&mut Tables, specialist:bool ) {
+    if setting.specialist != specialist {
+        update( setting, tables, Setting { specialist, ..setting.clone() }, Tables::recalc );
+    }
+    print_specialist( setting );
+}
+
+fn print_sustain( setting:&Setting ) {
+    println!("sustain: {}", if setting.sustain { "on" } else { "off" });
+}
+
+// バフの効果時間の延長を切り替えて表を計算し直します
+fn change_sustain( setting:&mut Setting, tables:&mut Tables, sustain:bool ) {
+    if setting.sustain != sustain {
+        update( setting, tables, Setting { sustain, ..setting.clone() }, Tables::recalc );
+    }
+    print_sustain( setting );
+}
+
+fn print_difficulty( setting:&Setting ) {
+    if setting.tracks_progress() {
+        println!("difficulty: {}", setting.difficulty);
+    }
+    else {
+        println!("difficulty: 0(progress not tracked)");
+    }
 }
 
-fn eval_line( ctx:&mut ReplContext, setting:&Setting, ta:&Table<Action>, line:&str ) -> bool {
+// 工数を変えて表を計算し直します。0にすると工数を追跡しません
+fn change_difficulty( setting:&mut Setting, tables:&mut Tables, difficulty:u32 ) {
+    if setting.difficulty != difficulty {
+        update( setting, tables, Setting { difficulty, ..setting.clone() }, Tables::recalc );
+    }
+    print_difficulty( setting );
+}
 
-    let cmdline = parse_cmdline( &line );
+fn print_max_quality( setting:&Setting ) {
+    if setting.max_quality > 0 {
+        println!("max quality: {}", setting.max_quality);
+    }
+    else {
+        println!("max quality: 0(no cap)");
+    }
+}
+
+// 最高品質を変えて表を計算し直します。HQ率を最大化している間は0にできません。
+// 上限のある表は開始品質0で計算し、違う開始品質は遅延評価で答えます
+fn change_max_quality( setting:&mut Setting, tables:&mut Tables, max_quality:u32 ) {
+    if max_quality == 0 && setting.objective == Objective::HqPercent {
+        println!("objective hq needs max quality(use objective quality first)");
+    }
+    else if setting.max_quality != max_quality {
+        update( setting, tables, Setting { max_quality, initial_quality:0, ..setting.clone() }, Tables::recalc );
+    }
+    print_max_quality( setting );
+}
+
+fn print_tiers( setting:&Setting ) {
+    if setting.collectability_tiers.is_empty() {
+        println!("tiers: off");
+    }
+    else {
+        let tiers : Vec<String> = setting.collectability_tiers.iter().map(|t| format!("{}:{}", t.collectability, t.reward)).collect();
+        println!("tiers: {}", tiers.join(" "));
+    }
+}
+
+// 収集品の段階を変えて表を計算し直します。最高品質と同じく開始品質0で計算します
+fn change_tiers( setting:&mut Setting, tables:&mut Tables, tiers:Vec<CollectabilityTier> ) {
+    if setting.collectability_tiers != tiers {
+        update( setting, tables, Setting { collectability_tiers:tiers, initial_quality:0, ..setting.clone() }, Tables::recalc );
+    }
+    print_tiers( setting );
+}
+
+fn print_reserve( setting:&Setting ) {
+    println!("reserve: CP {} durability {}", setting.reserve_cp, setting.reserve_durability);
+}
+
+// 仕上げの後に残すCPと耐久を変えて表を計算し直します
+fn change_reserve( setting:&mut Setting, tables:&mut Tables, (cp,durability):(u16,u8) ) {
+    if (setting.reserve_cp, setting.reserve_durability) != (cp, durability) {
+        update( setting, tables, Setting { reserve_cp:cp, reserve_durability:durability, ..setting.clone() }, Tables::recalc );
+    }
+    print_reserve( setting );
+}
+
+// 表を使わずに、調べる状態から遷移できる状態だけを評価して手順を表示します。
+// 開始品質は表を計算し直さずにこの評価にだけ使います
+fn print_lazy( ctx:&ReplContext, setting:&Setting, s:&State, quality:u32 ) {
+    let setting = Setting { initial_quality: quality, ..setting.clone() };
+    if setting.quality_cap().is_some() {
+        return print_capped( ctx, &setting, s );
+    }
+    let solver = match LazySolver::new( &setting, s ) {
+        Ok(solver) => solver,
+        Err(e) => return println!("{}", e),
+    };
+    if ctx.verbose && solver.contains(s) {
+        solver.action(s);
+        println!("(評価した状態の数: {})", solver.num_evaluated());
+    }
+    print_series( ctx, solver.setting(), &solver, s );
+}
+
+// 品質に上限や収集品の段階がある場合は、上げた品質も持つ遅延評価で手順を表示します。
+// 表は上げた品質を持たないので、上限までの残りや届く段階に合わせた手順になりません
+fn print_capped( ctx:&ReplContext, setting:&Setting, s:&State ) {
+    let solver = match QualitySolver::new( setting, s ) {
+        Ok(solver) => solver,
+        Err(e) => return println!("{}", e),
+    };
+    if ctx.verbose && solver.contains(s) {
+        solver.solve(s, 0);
+        println!("(評価した状態の数: {})", solver.num_evaluated());
+    }
+    print_series( ctx, solver.setting(), &solver, s );
+}
+
+// 最初のアクションの候補を評価値の高い順に並べて、それぞれの手順と、最善の手順と比べて失う品質の期待値を表示します。
+// 今の設定の表で比べます
+fn print_alternatives( ctx:&ReplContext, setting:&Setting, tables:&Tables, s:&State, k:usize ) {
+    if !tables.values.contains(s) {
+        print_out_of_bound( setting );
+        return;
+    }
+
+    let actions = best_actions( setting, &tables.values, s, k );
+    print_ranked( ctx, setting, s, &actions, &tables.actions );
+}
+
+// 品質に上限や収集品の段階がある場合は、表の代わりに上げた品質も持つ遅延評価で比べます。開始品質もこの評価にだけ使います
+fn print_capped_alternatives( ctx:&ReplContext, setting:&Setting, s:&State, quality:u32, k:usize ) {
+    let setting = Setting { initial_quality: quality, ..setting.clone() };
+    let solver = match QualitySolver::new( &setting, s ) {
+        Ok(solver) => solver,
+        Err(e) => return println!("{}", e),
+    };
+    if !solver.contains(s) {
+        print_out_of_bound( &setting );
+        return;
+    }
+
+    let actions : Vec<(Action,u32)> = solver.best_actions( s, 0, k ).into_iter().map(|(a,v)| (a, from_utility( solver.setting(), v.utility as u64 ) as u32)).collect();
+    print_ranked( ctx, solver.setting(), s, &actions, &solver );
+}
+
+fn print_ranked( ctx:&ReplContext, setting:&Setting, s:&State, actions:&[(Action,u32)], rest:&dyn Policy ) {
+    if actions.is_empty() {
+        println!("使えるアクションがありません");
+        println!();
+        return;
+    }
+
+    let best = actions[0].1;
+    for (i,(a,q)) in actions.iter().enumerate() {
+        if i == 0 {
+            println!("[{}] {} 期待値:+{}", i + 1, action_name(setting, a), q);
+        }
+        else {
+            println!("[{}] {} 期待値:+{}(-{})", i + 1, action_name(setting, a), q, best.saturating_sub(*q));
+        }
+        print_series( ctx, setting, &FirstAction { state:*s, action:*a, rest }, s );
+    }
+}
+
+fn eval_line( ctx:&mut ReplContext, setting:&mut Setting, tables:&mut Tables, line:&str ) -> bool {
+
+    let cmdline = parse_cmdline( setting, line );
 
     match cmdline {
         Ok(cmd) => match cmd {
-            CmdLine::Eval(s) => print_series(ctx,setting,ta,&s),
-            CmdLine::Help => print_help(),
+            CmdLine::Eval(s,q) => {
+                // 品質に上限や収集品の段階がある場合は、表の代わりに上げた品質も持つ遅延評価で答えます
+                if setting.quality_cap().is_some() {
+                    print_lazy(ctx,setting,&s,q)
+                }
+                else {
+                    print_series(ctx,&Setting { initial_quality:q, ..setting.clone() },&tables.actions,&s)
+                }
+            },
+            CmdLine::Lazy(s,q) => print_lazy(ctx,setting,&s,q),
+            CmdLine::Alternatives(s,q,k) => {
+                if setting.quality_cap().is_some() {
+                    print_capped_alternatives(ctx,setting,&s,q,k)
+                }
+                else {
+                    print_alternatives(ctx,&Setting { initial_quality:q, ..setting.clone() },tables,&s,k)
+                }
+            },
+            CmdLine::Rules(None) => println!("rules: {}", setting.rules().name()),
+            CmdLine::Rules(Some(r)) => change_rules(setting,tables,r),
+            CmdLine::LoadRules(path) => load_rules(setting,tables,&path),
+            CmdLine::Formula(None) => print_formula(setting),
+            CmdLine::Formula(Some(f)) => change_formula(setting,tables,f),
+            CmdLine::Objective(None) => print_objective(setting),
+            CmdLine::Objective(Some(o)) => change_objective(setting,tables,o),
+            CmdLine::Reserve(None) => print_reserve(setting),
+            CmdLine::Reserve(Some(r)) => change_reserve(setting,tables,r),
+            CmdLine::Level(None) => print_level(setting),
+            CmdLine::Level(Some(l)) => change_level(setting,tables,l),
+            CmdLine::MaxCp(None) => print_max_cp(setting),
+            CmdLine::MaxCp(Some(cp)) => change_max_cp(setting,tables,cp),
+            CmdLine::Conditions(None) => print_conditions(setting),
+            CmdLine::Conditions(Some(c)) => change_conditions(setting,tables,c),
+            CmdLine::Specialist(None) => print_specialist(setting),
+            CmdLine::Specialist(Some(x)) => change_specialist(setting,tables,x),
+            CmdLine::Sustain(None) => print_sustain(setting),
+            CmdLine::Sustain(Some(x)) => change_sustain(setting,tables,x),
+            CmdLine::Difficulty(None) => print_difficulty(setting),
+            CmdLine::Difficulty(Some(d)) => change_difficulty(setting,tables,d),
+            CmdLine::MaxQuality(None) => print_max_quality(setting),
+            CmdLine::MaxQuality(Some(q)) => change_max_quality(setting,tables,q),
+            CmdLine::Tiers(None) => print_tiers(setting),
+            CmdLine::Tiers(Some(t)) => change_tiers(setting,tables,t),
+            CmdLine::Help => print_help(setting),
             CmdLine::Empty => {},
             CmdLine::Verbose => { *ctx = ReplContext{ verbose: true } },
             CmdLine::Simple => { *ctx = ReplContext{ verbose: false } },
@@ -161,12 +882,13 @@ fn eval_line( ctx:&mut ReplContext, setting:&Setting, ta:&Table<Action>, line:&s
         },
     }
 
-    return true
+    true
 }
 
-pub fn repl( setting:&Setting, ta:&Table<Action> ) {
+pub fn repl( mut setting:Setting, tv:Table<u32>, ta:ActionTable ) {
 
     let mut ctx = ReplContext { verbose: true };
+    let mut tables = Tables { values: tv, actions: ta, setting: setting.table_setting() };
 
     let mut rl = Editor::<()>::new();
     loop {
@@ -174,7 +896,7 @@ pub fn repl( setting:&Setting, ta:&Table<Action> ) {
         match readline {
             Ok(line) => {
                 rl.add_history_entry(line.as_str());
-                if !eval_line( &mut ctx, &setting, &ta, &line ) {
+                if !eval_line( &mut ctx, &mut setting, &mut tables, &line ) {
                     break;
                 }
             },
@@ -191,3 +913,19 @@ pub fn repl( setting:&Setting, ta:&Table<Action> ) {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 表に載っていないレシピレベルは、載っているレベルの一覧を示して断ります
+    #[test]
+    fn rejects_unknown_recipe_level() {
+        let supported : Vec<String> = (0..=u16::MAX).filter(|x| RecipeLevel::find(*x).is_some()).map(|x| x.to_string()).collect();
+        let message = format!("unknown recipe level(supported: {}, or use approx)", supported.join(" "));
+        for x in ["600", "630", "100"] {
+            assert_eq!( parse_formula( &[x] ).unwrap_err(), message );
+        }
+        assert!( matches!( parse_formula( &["560"] ), Ok(CmdLine::Formula(Some(QualityFormula::Exact(_)))) ) );
+    }
+}
@@ -0,0 +1,329 @@
+use core::cmp::min;
+use crate::logic::{State,Action,Setting};
+use super::{CraftRules,Actions,MAX_CAREFUL_OBSERVATION,MAX_CP_RESTORE,base_quality,quality_rate,trained_eye_usable,trait_efficiency};
+
+// パッチ5.5のルールです
+pub struct Patch5;
+
+const CANDIDATE_ACTIONS : [Action;32] = [
+    Action::BasicTouch,         // 加工
+    Action::StandardTouch,      // 中級加工
+    Action::PrudentTouch,       // 倹約加工
+    Action::FocusedTouch,       // 注視加工
+    Action::PreparatoryTouch,   // 下地加工
+    Action::ByregotsBlessing,   // ビエルゴの祝福
+    Action::MastersMend,        // マスターズメンド
+    Action::Observe,            // 経過観察
+    Action::WasteNot,           // 倹約
+    Action::WasteNot2,          // 長期倹約
+    Action::GreatStrides,       // グレートストライド
+    Action::Innovation,         // イノベーション
+    Action::Manipulation,       // マニピュレーション
+    Action::InnerQuiet,         // インナークワイエット
+    Action::PreciseTouch,       // 集中加工
+    Action::TricksOfTheTrade,   // 秘訣
+    Action::HastyTouch,         // ヘイスティタッチ
+    Action::PatientTouch,       // 専心加工
+    Action::BasicSynthesis,     // 作業
+    Action::CarefulSynthesis,   // 模範作業
+    Action::RapidSynthesis,     // 突貫作業
+    Action::IntensiveSynthesis, // 集中作業
+    Action::Groundwork,         // 下地作業
+    Action::DelicateSynthesis,  // 精密作業
+    Action::BrandOfTheElements, // ブランド・オブ・エレメント
+    Action::Veneration,         // ヴェネレーション
+    Action::NameOfTheElements,  // ネーム・オブ・エレメント
+    Action::CarefulObservation, // 設計変更
+    Action::Reflect,            // 確信
+    Action::MuscleMemory,       // 確信作業
+    Action::TrainedEye,         // 匠の早業
+    Action::FinalAppraisal,     // 最終確認
+];
+
+// インナークワイエットの最大スタック数です(発動時の1スタックを含みます)
+const MAX_INNER_QUIET : u8 = 11;
+
+// 5.xのインナークワイエットはアクションで発動させてからスタックが溜まります
+trait InnerQuiet {
+    fn double_inner_quiet(&self) -> State;
+    fn halve_inner_quiet(&self) -> State;
+}
+
+impl InnerQuiet for State {
+    // 専心加工の成功時はスタックが倍になります
+    fn double_inner_quiet(&self) -> State {
+        self.set_inner_quiet(min(self.buff.inner_quiet * 2, MAX_INNER_QUIET))
+    }
+
+    // 専心加工の失敗時はスタックが半分(切り上げ)になります
+    fn halve_inner_quiet(&self) -> State {
+        self.set_inner_quiet(self.buff.inner_quiet.div_ceil(2))
+    }
+}
+
+impl Patch5 {
+    // ブランド・オブ・エレメントはネーム・オブ・エレメントの効果中、残りの工数に応じて最大200%効率が上がります
+    fn brand_of_the_elements_efficiency(&self, s:&State, setting:&Setting) -> u32 {
+        if s.buff.name_of_the_elements > 0 {
+            let required = setting.required_progress() as u32;
+            100 + 200 * (required - min(s.progress as u32, required)) / required
+        }
+        else {
+            100
+        }
+    }
+
+    fn run_inner_quiet(&self, s:&State, setting:&Setting) -> (State,u32) {
+        (self.consume(s,&Action::InnerQuiet).next_turn(setting).set_inner_quiet(1),0)
+    }
+
+    fn run_patient_touch(&self, s:&State, setting:&Setting) -> (State,u32) {
+        (self.consume(s,&Action::PatientTouch).next_turn(setting).set_great_strides(0).double_inner_quiet(), self.quality_reward(s,setting,1.0))
+    }
+
+    fn run_name_of_the_elements(&self, s:&State, setting:&Setting) -> (State,u32) {
+        (self.consume(s,&Action::NameOfTheElements).next_turn(setting).set_name_of_the_elements(3),0)
+    }
+}
+
+// 5.5で6.0以降と効果の違うアクションです
+impl Actions for Patch5 {
+    // 効率に対する品質報酬
+    // 5.xではインナークワイエットのスタックが加工精度を上げます。
+    // レシピレベルの表を使った計算式は6.0以降のものなので、5.xでは常に近似式を使います
+    fn quality_reward(&self, s:&State, setting:&Setting, efficiency : f64) -> u32 {
+        let inner_quiet : f64 = From::from(s.buff.inner_quiet);
+        let process_accuracy : f64 = From::from(setting.process_accuracy);
+
+        let f = if inner_quiet == 0.0 { process_accuracy } else { process_accuracy + process_accuracy * ((inner_quiet-1.0) * 20.0 / 100.0) };
+        let q3 = base_quality(setting, f);
+        let cond_rate = quality_rate(&s.condition);
+        let buff_rate = 1.0 + if s.buff.great_strides > 0 { 1.0 } else { 0.0 } + if s.buff.innovation > 0 { 0.5 } else { 0.0 };
+
+        ( q3 * cond_rate * efficiency * buff_rate ) as u32
+    }
+
+    // インナークワイエットが無効の時は何も起きません
+    fn add_inner_quiet(&self, s:&State, x:u8) -> State {
+        if s.buff.inner_quiet == 0 { *s } else { s.set_inner_quiet(min(s.buff.inner_quiet + x, MAX_INNER_QUIET)) }
+    }
+
+    // 発動時の1スタックは数えません
+    fn byregots_quality_reward(&self, s:&State, setting:&Setting) -> u32 {
+        self.quality_reward(s, setting, 1.0 + (s.buff.inner_quiet-1) as f64 * 0.2)
+    }
+
+    // 5.xには上級加工が無いのでコンボは続きません
+    fn run_standard_touch(&self, s:&State, setting:&Setting) -> (State,u32) {
+        self.run_touch(s, setting, &Action::StandardTouch, 1, 1.25)
+    }
+
+    // 5.xの確信はインナークワイエットを3スタックで発動させます
+    fn run_reflect(&self, s:&State, setting:&Setting) -> (State,u32) {
+        (self.consume(s,&Action::Reflect).next_turn(setting).set_great_strides(0).set_inner_quiet(3), self.quality_reward(s,setting,1.0))
+    }
+}
+
+impl CraftRules for Patch5 {
+    fn name(&self) -> &'static str {
+        "5.5"
+    }
+
+    fn candidate_actions(&self) -> &'static [Action] {
+        &CANDIDATE_ACTIONS
+    }
+
+    fn max_inner_quiet(&self) -> u8 {
+        MAX_INNER_QUIET
+    }
+
+    fn max_level(&self) -> u8 {
+        80
+    }
+
+    fn required_cp(&self, s:&State, a:&Action) -> u16 {
+        match a {
+            Action::CannotAction => 0,
+            Action::BasicTouch => 18,
+            Action::StandardTouch => if s.buff.basic_touch > 0 { 18 } else { 32 },
+            Action::PrudentTouch => 25,
+            Action::FocusedTouch => 18,
+            Action::PreparatoryTouch => 40,
+            Action::ByregotsBlessing => 24,
+            Action::MastersMend => 88,
+            Action::Observe => 7,
+            Action::WasteNot => 56,
+            Action::WasteNot2 => 98,
+            Action::GreatStrides => 32,
+            Action::Innovation => 18,
+            Action::Manipulation => 96,
+            Action::InnerQuiet => 18,
+            Action::PreciseTouch => 18,
+            Action::TricksOfTheTrade => 0,
+            Action::HastyTouch => 0,
+            Action::PatientTouch => 6,
+            Action::BasicSynthesis => 0,
+            Action::CarefulSynthesis => 7,
+            Action::RapidSynthesis => 0,
+            Action::IntensiveSynthesis => 6,
+            Action::Groundwork => 18,
+            Action::DelicateSynthesis => 32,
+            Action::BrandOfTheElements => 6,
+            Action::Veneration => 18,
+            Action::NameOfTheElements => 30,
+            Action::CarefulObservation => 0,
+            Action::HeartAndSoul => 0,
+            Action::Reflect => 24,
+            Action::MuscleMemory => 6,
+            Action::TrainedEye => 250,
+            Action::FinalAppraisal => 1,
+            Action::AdvancedTouch | Action::TrainedFinesse | Action::PrudentSynthesis => 0,
+            Action::RefinedTouch | Action::DaringTouch | Action::QuickInnovation | Action::TrainedPerfection | Action::ImmaculateMend => 0,
+            Action::Data(_) => 0,
+        }
+    }
+
+    fn required_durability(&self, s:&State, a:&Action) -> u8 {
+        let d = match a {
+            Action::CannotAction => 0,
+            Action::BasicTouch => 10,
+            Action::StandardTouch => 10,
+            Action::PrudentTouch => 5,
+            Action::FocusedTouch => 10,
+            Action::PreparatoryTouch => 20,
+            Action::ByregotsBlessing => 10,
+            Action::MastersMend => 0,
+            Action::Observe => 0,
+            Action::WasteNot => 0,
+            Action::WasteNot2 => 0,
+            Action::GreatStrides => 0,
+            Action::Innovation => 0,
+            Action::Manipulation => 0,
+            Action::InnerQuiet => 0,
+            Action::PreciseTouch => 10,
+            Action::TricksOfTheTrade => 0,
+            Action::HastyTouch => 10,
+            Action::PatientTouch => 10,
+            Action::BasicSynthesis => 10,
+            Action::CarefulSynthesis => 10,
+            Action::RapidSynthesis => 10,
+            Action::IntensiveSynthesis => 10,
+            Action::Groundwork => 20,
+            Action::DelicateSynthesis => 10,
+            Action::BrandOfTheElements => 10,
+            Action::Veneration => 0,
+            Action::NameOfTheElements => 0,
+            Action::CarefulObservation => 0,
+            Action::HeartAndSoul => 0,
+            Action::Reflect => 10,
+            Action::MuscleMemory => 10,
+            Action::TrainedEye => 10,
+            Action::FinalAppraisal => 0,
+            Action::AdvancedTouch | Action::TrainedFinesse | Action::PrudentSynthesis => 0,
+            Action::RefinedTouch | Action::DaringTouch | Action::QuickInnovation | Action::TrainedPerfection | Action::ImmaculateMend => 0,
+            Action::Data(_) => 0,
+        };
+
+        if s.buff.waste_not > 0 { d / 2 } else { d }
+    }
+
+    fn usable(&self, s:&State, setting:&Setting, a:&Action) -> bool {
+        match a {
+            Action::ByregotsBlessing => s.buff.inner_quiet >= 1, // ビエルゴはinner_quietが1以上の時に使えます
+            Action::PrudentTouch => s.buff.waste_not == 0,       // 倹約加工は倹約が無効の時に使えます
+            Action::InnerQuiet => s.buff.inner_quiet == 0,       // インナークワイエットは効果が切れている時のみ使えます
+            Action::PreciseTouch => s.condition.is_good(),       // 集中加工は高品質以上の時のみ使えます
+            Action::TricksOfTheTrade => s.condition.is_good(),   // 秘訣は高品質以上の時のみ使えます
+            Action::IntensiveSynthesis => s.condition.is_good(), // 集中作業は高品質以上の時のみ使えます
+            Action::NameOfTheElements => s.buff.name_of_the_elements_used == 0, // ネーム・オブ・エレメントは1回の製作で1度だけ使えます
+            Action::Reflect => s.buff.first_step > 0,            // 確信は最初の工程でのみ使えます
+            Action::MuscleMemory => s.buff.first_step > 0,       // 確信作業は最初の工程でのみ使えます
+            Action::TrainedEye => s.buff.first_step > 0 && trained_eye_usable(setting), // 匠の早業は最初の工程でのみ使えます
+            Action::CarefulObservation => setting.conditions && s.buff.careful_observation_used < MAX_CAREFUL_OBSERVATION, // 設計変更は1回の製作で3度まで使えます(状態を考えない場合は何も変わらないので使いません)
+            Action::HeartAndSoul => false, // 6.0で追加されたアクションです
+            Action::AdvancedTouch | Action::TrainedFinesse | Action::PrudentSynthesis => false, // 6.0で追加されたアクションです
+            Action::RefinedTouch | Action::DaringTouch | Action::QuickInnovation | Action::TrainedPerfection | Action::ImmaculateMend => false, // 7.0で追加されたアクションです
+            Action::Data(_) => false, // データファイルのルールでだけ使えます
+            _ => true
+        }
+    }
+
+    // 注視加工は経過観察の直後だけ必ず成功します
+    fn success_rate(&self, s:&State, a:&Action) -> u32 {
+        match a {
+            Action::FocusedTouch => if s.buff.observe > 0 { 100 } else { 50 },
+            Action::HastyTouch => 60,
+            Action::PatientTouch => 50,
+            Action::RapidSynthesis => 50,
+            _ => 100,
+        }
+    }
+
+    fn run_succeeded_action(&self, s:&State, setting:&Setting, a:&Action) -> (State,u32) {
+        match a {
+            Action::CannotAction => panic!("Cannot run action"),
+            Action::BasicTouch => self.run_basic_touch(s, setting),
+            Action::StandardTouch => self.run_standard_touch(s, setting),
+            Action::PrudentTouch => self.run_touch(s, setting, a, 1, 1.0),
+            Action::FocusedTouch => self.run_touch(s, setting, a, 1, 1.5),
+            Action::PreparatoryTouch => self.run_touch(s, setting, a, 2, 2.0),
+            Action::ByregotsBlessing => self.run_byregots_blessing(s, setting),
+            Action::MastersMend => self.run_masters_mend(s, setting),
+            Action::Observe => self.run_observe(s, setting),
+            Action::WasteNot => self.run_waste_not(s, setting),
+            Action::WasteNot2 => self.run_waste_not_2(s, setting),
+            Action::GreatStrides => self.run_great_strides(s, setting),
+            Action::Innovation => self.run_innovation(s, setting),
+            Action::Manipulation => self.run_manipulation(s, setting),
+            Action::InnerQuiet => self.run_inner_quiet(s, setting),
+            Action::PreciseTouch => self.run_precise_touch(s, setting),
+            Action::TricksOfTheTrade => self.run_tricks_of_the_trade(s, setting),
+            Action::HastyTouch => self.run_hasty_touch(s, setting),
+            Action::PatientTouch => self.run_patient_touch(s, setting),
+            Action::BasicSynthesis => self.run_synthesis(s, setting, a, trait_efficiency(setting, 31, 100, 120)),
+            Action::CarefulSynthesis => self.run_synthesis(s, setting, a, 150),
+            Action::RapidSynthesis => self.run_synthesis(s, setting, a, trait_efficiency(setting, 63, 250, 500)),
+            Action::IntensiveSynthesis => self.run_intensive_synthesis(s, setting),
+            Action::Groundwork => self.run_synthesis(s, setting, a, 300),
+            Action::DelicateSynthesis => self.run_delicate_synthesis(s, setting),
+            Action::BrandOfTheElements => self.run_synthesis(s, setting, a, self.brand_of_the_elements_efficiency(s, setting)),
+            Action::Veneration => self.run_veneration(s, setting),
+            Action::NameOfTheElements => self.run_name_of_the_elements(s, setting),
+            Action::CarefulObservation => self.run_careful_observation(s),
+            Action::Reflect => self.run_reflect(s, setting),
+            Action::MuscleMemory => self.run_muscle_memory(s, setting),
+            Action::TrainedEye => self.run_trained_eye(s, setting),
+            Action::FinalAppraisal => self.run_final_appraisal(s, setting),
+            Action::HeartAndSoul => panic!("Not available in 5.5"),
+            Action::AdvancedTouch | Action::TrainedFinesse | Action::PrudentSynthesis => panic!("Not available in 5.5"),
+            Action::RefinedTouch | Action::DaringTouch | Action::QuickInnovation | Action::TrainedPerfection | Action::ImmaculateMend => panic!("Not available in 5.5"),
+            Action::Data(_) => panic!("Not available in 5.5"),
+        }
+    }
+
+    // 専心加工は失敗するとスタックが半分になります
+    fn run_failed_action(&self, s:&State, setting:&Setting, a:&Action) -> State {
+        let ns = self.consume(s, a).next_turn(setting);
+
+        match a {
+            Action::PatientTouch => ns.halve_inner_quiet(),
+            _ => ns,
+        }
+    }
+
+    fn keeps_condition(&self, a:&Action) -> bool {
+        matches!(a, Action::FinalAppraisal)
+    }
+
+    fn restores_cp(&self, a:&Action) -> bool {
+        matches!(a, Action::TricksOfTheTrade)
+    }
+
+    fn max_cp_restore(&self) -> u16 {
+        MAX_CP_RESTORE
+    }
+
+    fn exact_formula(&self) -> bool {
+        false
+    }
+}
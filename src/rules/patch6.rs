@@ -0,0 +1,262 @@
+use crate::logic::{State,Action,Setting};
+use super::{CraftRules,Actions,MAX_CAREFUL_OBSERVATION,MAX_CP_RESTORE,trained_eye_usable,trait_efficiency};
+
+// パッチ6.xのルールです。効率はクラフターのレベルで習得済みの特性に合わせます
+pub struct Patch6;
+
+const CANDIDATE_ACTIONS : [Action;32] = [
+    Action::BasicTouch,         // 加工
+    Action::StandardTouch,      // 中級加工
+    Action::AdvancedTouch,      // 上級加工
+    Action::PrudentTouch,       // 倹約加工
+    Action::FocusedTouch,       // 注視加工
+    Action::PreparatoryTouch,   // 下地加工
+    Action::TrainedFinesse,     // 匠の絶技
+    Action::ByregotsBlessing,   // ビエルゴの祝福
+    Action::MastersMend,        // マスターズメンド
+    Action::Observe,            // 経過観察
+    Action::WasteNot,           // 倹約
+    Action::WasteNot2,          // 長期倹約
+    Action::GreatStrides,       // グレートストライド
+    Action::Innovation,         // イノベーション
+    Action::Manipulation,       // マニピュレーション
+    Action::PreciseTouch,       // 集中加工
+    Action::TricksOfTheTrade,   // 秘訣
+    Action::HastyTouch,         // ヘイスティタッチ
+    Action::BasicSynthesis,     // 作業
+    Action::CarefulSynthesis,   // 模範作業
+    Action::RapidSynthesis,     // 突貫作業
+    Action::IntensiveSynthesis, // 集中作業
+    Action::Groundwork,         // 下地作業
+    Action::DelicateSynthesis,  // 精密作業
+    Action::PrudentSynthesis,   // 倹約作業
+    Action::Veneration,         // ヴェネレーション
+    Action::CarefulObservation, // 設計変更
+    Action::Reflect,            // 確信
+    Action::MuscleMemory,       // 確信作業
+    Action::TrainedEye,         // 匠の早業
+    Action::FinalAppraisal,     // 最終確認
+    Action::HeartAndSoul,       // 一心不乱
+];
+
+// インナークワイエットの最大スタック数です
+const MAX_INNER_QUIET : u8 = 10;
+
+impl Patch6 {
+    // 倹約の効果が無い時に消費する耐久です
+    pub(super) fn base_durability(a:&Action) -> u8 {
+        match a {
+            Action::CannotAction => 0,
+            Action::BasicTouch => 10,
+            Action::StandardTouch => 10,
+            Action::AdvancedTouch => 10,
+            Action::PrudentTouch => 5,
+            Action::FocusedTouch => 10,
+            Action::PreparatoryTouch => 20,
+            Action::TrainedFinesse => 0,
+            Action::ByregotsBlessing => 10,
+            Action::MastersMend => 0,
+            Action::Observe => 0,
+            Action::WasteNot => 0,
+            Action::WasteNot2 => 0,
+            Action::GreatStrides => 0,
+            Action::Innovation => 0,
+            Action::Manipulation => 0,
+            Action::PreciseTouch => 10,
+            Action::TricksOfTheTrade => 0,
+            Action::HastyTouch => 10,
+            Action::BasicSynthesis => 10,
+            Action::CarefulSynthesis => 10,
+            Action::RapidSynthesis => 10,
+            Action::IntensiveSynthesis => 10,
+            Action::Groundwork => 20,
+            Action::DelicateSynthesis => 10,
+            Action::PrudentSynthesis => 5,
+            Action::Veneration => 0,
+            Action::CarefulObservation => 0,
+            Action::HeartAndSoul => 0,
+            Action::Reflect => 10,
+            Action::MuscleMemory => 10,
+            Action::TrainedEye => 10,
+            Action::FinalAppraisal => 0,
+            Action::InnerQuiet | Action::PatientTouch | Action::BrandOfTheElements | Action::NameOfTheElements => 0,
+            Action::RefinedTouch | Action::DaringTouch | Action::QuickInnovation | Action::TrainedPerfection | Action::ImmaculateMend => 0,
+            Action::Data(_) => 0,
+        }
+    }
+}
+
+// 6.0で追加されたアクションの効果です。7.xのルールも効果が変わっていないアクションはここを使います。
+// CPと耐久の消費はルールのconsumeに従います
+pub(super) trait Patch6Actions : Actions {
+    // 下地作業は耐久が足りない時は効率が半分になります
+    fn groundwork_efficiency(&self, s:&State, setting:&Setting) -> u32 {
+        let efficiency = trait_efficiency(setting, 86, 300, 360);
+        if s.durability < self.required_durability(s, &Action::Groundwork) { efficiency / 2 } else { efficiency }
+    }
+
+    fn run_trained_finesse(&self, s:&State, setting:&Setting) -> (State,u32) {
+        (self.consume(s,&Action::TrainedFinesse).next_turn(setting).set_great_strides(0), self.quality_reward(s,setting,1.0))
+    }
+
+    // 一心不乱も工程を進めません
+    fn run_heart_and_soul(&self, s:&State) -> (State,u32) {
+        (s.set_heart_and_soul(),0)
+    }
+
+    // 6.xにあるアクションを成功させた時の状態と品質です
+    fn run_patch6_action(&self, s:&State, setting:&Setting, a:&Action) -> (State,u32) {
+        match a {
+            Action::CannotAction => panic!("Cannot run action"),
+            Action::BasicTouch => self.run_basic_touch(s, setting),
+            Action::StandardTouch => self.run_standard_touch(s, setting),
+            Action::AdvancedTouch => self.run_touch(s, setting, a, 1, 1.5),
+            Action::PrudentTouch => self.run_touch(s, setting, a, 1, 1.0),
+            Action::FocusedTouch => self.run_touch(s, setting, a, 1, 1.5),
+            Action::PreparatoryTouch => self.run_touch(s, setting, a, 2, 2.0),
+            Action::TrainedFinesse => self.run_trained_finesse(s, setting),
+            Action::ByregotsBlessing => self.run_byregots_blessing(s, setting),
+            Action::MastersMend => self.run_masters_mend(s, setting),
+            Action::Observe => self.run_observe(s, setting),
+            Action::WasteNot => self.run_waste_not(s, setting),
+            Action::WasteNot2 => self.run_waste_not_2(s, setting),
+            Action::GreatStrides => self.run_great_strides(s, setting),
+            Action::Innovation => self.run_innovation(s, setting),
+            Action::Manipulation => self.run_manipulation(s, setting),
+            Action::PreciseTouch => self.run_precise_touch(s, setting),
+            Action::TricksOfTheTrade => self.run_tricks_of_the_trade(s, setting),
+            Action::HastyTouch => self.run_hasty_touch(s, setting),
+            Action::BasicSynthesis => self.run_synthesis(s, setting, a, trait_efficiency(setting, 31, 100, 120)),
+            Action::CarefulSynthesis => self.run_synthesis(s, setting, a, trait_efficiency(setting, 82, 150, 180)),
+            Action::RapidSynthesis => self.run_synthesis(s, setting, a, trait_efficiency(setting, 63, 250, 500)),
+            Action::IntensiveSynthesis => self.run_intensive_synthesis(s, setting),
+            Action::Groundwork => self.run_synthesis(s, setting, a, self.groundwork_efficiency(s, setting)),
+            Action::DelicateSynthesis => self.run_delicate_synthesis(s, setting),
+            Action::PrudentSynthesis => self.run_synthesis(s, setting, a, 180),
+            Action::Veneration => self.run_veneration(s, setting),
+            Action::CarefulObservation => self.run_careful_observation(s),
+            Action::Reflect => self.run_reflect(s, setting),
+            Action::MuscleMemory => self.run_muscle_memory(s, setting),
+            Action::TrainedEye => self.run_trained_eye(s, setting),
+            Action::FinalAppraisal => self.run_final_appraisal(s, setting),
+            Action::HeartAndSoul => self.run_heart_and_soul(s),
+            _ => panic!("Not available in {}", self.name()),
+        }
+    }
+}
+
+impl Actions for Patch6 {}
+
+impl Patch6Actions for Patch6 {}
+
+impl CraftRules for Patch6 {
+    fn name(&self) -> &'static str {
+        "6.x"
+    }
+
+    fn candidate_actions(&self) -> &'static [Action] {
+        &CANDIDATE_ACTIONS
+    }
+
+    fn max_inner_quiet(&self) -> u8 {
+        MAX_INNER_QUIET
+    }
+
+    fn max_level(&self) -> u8 {
+        90
+    }
+
+    fn required_cp(&self, s:&State, a:&Action) -> u16 {
+        match a {
+            Action::CannotAction => 0,
+            Action::BasicTouch => 18,
+            Action::StandardTouch => if s.buff.basic_touch > 0 { 18 } else { 32 },
+            Action::AdvancedTouch => if s.buff.standard_touch > 0 { 18 } else { 46 },
+            Action::PrudentTouch => 25,
+            Action::FocusedTouch => 18,
+            Action::PreparatoryTouch => 40,
+            Action::TrainedFinesse => 32,
+            Action::ByregotsBlessing => 24,
+            Action::MastersMend => 88,
+            Action::Observe => 7,
+            Action::WasteNot => 56,
+            Action::WasteNot2 => 98,
+            Action::GreatStrides => 32,
+            Action::Innovation => 18,
+            Action::Manipulation => 96,
+            Action::PreciseTouch => 18,
+            Action::TricksOfTheTrade => 0,
+            Action::HastyTouch => 0,
+            Action::BasicSynthesis => 0,
+            Action::CarefulSynthesis => 7,
+            Action::RapidSynthesis => 0,
+            Action::IntensiveSynthesis => 6,
+            Action::Groundwork => 18,
+            Action::DelicateSynthesis => 32,
+            Action::PrudentSynthesis => 18,
+            Action::Veneration => 18,
+            Action::CarefulObservation => 0,
+            Action::HeartAndSoul => 0,
+            Action::Reflect => 6,
+            Action::MuscleMemory => 6,
+            Action::TrainedEye => 250,
+            Action::FinalAppraisal => 1,
+            Action::InnerQuiet | Action::PatientTouch | Action::BrandOfTheElements | Action::NameOfTheElements => 0,
+            Action::RefinedTouch | Action::DaringTouch | Action::QuickInnovation | Action::TrainedPerfection | Action::ImmaculateMend => 0,
+            Action::Data(_) => 0,
+        }
+    }
+
+    fn required_durability(&self, s:&State, a:&Action) -> u8 {
+        let d = Patch6::base_durability(a);
+
+        if s.buff.waste_not > 0 { d / 2 } else { d }
+    }
+
+    fn usable(&self, s:&State, setting:&Setting, a:&Action) -> bool {
+        match a {
+            Action::ByregotsBlessing => s.buff.inner_quiet >= 1, // ビエルゴはinner_quietが1以上の時に使えます
+            Action::PrudentTouch => s.buff.waste_not == 0,       // 倹約加工は倹約が無効の時に使えます
+            Action::PrudentSynthesis => s.buff.waste_not == 0,   // 倹約作業は倹約が無効の時に使えます
+            Action::TrainedFinesse => s.buff.inner_quiet == MAX_INNER_QUIET, // 匠の絶技はインナークワイエットが最大の時のみ使えます
+            Action::PreciseTouch => s.condition.is_good() || s.buff.heart_and_soul > 0,       // 集中加工は高品質以上か一心不乱の効果中のみ使えます
+            Action::TricksOfTheTrade => s.condition.is_good() || s.buff.heart_and_soul > 0,   // 秘訣は高品質以上か一心不乱の効果中のみ使えます
+            Action::IntensiveSynthesis => s.condition.is_good() || s.buff.heart_and_soul > 0, // 集中作業は高品質以上か一心不乱の効果中のみ使えます
+            Action::Reflect => s.buff.first_step > 0,            // 確信は最初の工程でのみ使えます
+            Action::MuscleMemory => s.buff.first_step > 0,       // 確信作業は最初の工程でのみ使えます
+            Action::TrainedEye => s.buff.first_step > 0 && trained_eye_usable(setting), // 匠の早業は最初の工程でのみ使えます
+            Action::CarefulObservation => setting.conditions && s.buff.careful_observation_used < MAX_CAREFUL_OBSERVATION, // 設計変更は1回の製作で3度まで使えます(状態を考えない場合は何も変わらないので使いません)
+            Action::HeartAndSoul => s.buff.heart_and_soul_used == 0 && s.buff.heart_and_soul == 0, // 一心不乱は1回の製作で1度だけ使えます
+            Action::InnerQuiet | Action::PatientTouch | Action::BrandOfTheElements | Action::NameOfTheElements => false, // 6.0で削除されたアクションです
+            Action::RefinedTouch | Action::DaringTouch | Action::QuickInnovation | Action::TrainedPerfection | Action::ImmaculateMend => false, // 7.0で追加されたアクションです
+            Action::Data(_) => false, // データファイルのルールでだけ使えます
+            _ => true
+        }
+    }
+
+    // 注視加工は経過観察の直後だけ必ず成功します
+    fn success_rate(&self, s:&State, a:&Action) -> u32 {
+        match a {
+            Action::FocusedTouch => if s.buff.observe > 0 { 100 } else { 50 },
+            Action::HastyTouch => 60,
+            Action::RapidSynthesis => 50,
+            _ => 100,
+        }
+    }
+
+    fn run_succeeded_action(&self, s:&State, setting:&Setting, a:&Action) -> (State,u32) {
+        self.run_patch6_action(s, setting, a)
+    }
+
+    fn keeps_condition(&self, a:&Action) -> bool {
+        matches!(a, Action::HeartAndSoul | Action::FinalAppraisal)
+    }
+
+    fn restores_cp(&self, a:&Action) -> bool {
+        matches!(a, Action::TricksOfTheTrade)
+    }
+
+    fn max_cp_restore(&self) -> u16 {
+        MAX_CP_RESTORE
+    }
+}
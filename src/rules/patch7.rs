@@ -0,0 +1,185 @@
+use crate::logic::{State,Action,Setting};
+use super::{CraftRules,Actions,trait_efficiency};
+use super::patch6::{Patch6,Patch6Actions};
+
+// パッチ7.xのルールです。効果が変わっていないアクションは6.xのルールに任せます
+pub struct Patch7;
+
+const CANDIDATE_ACTIONS : [Action;36] = [
+    Action::BasicTouch,         // 加工
+    Action::StandardTouch,      // 中級加工
+    Action::AdvancedTouch,      // 上級加工
+    Action::PrudentTouch,       // 倹約加工
+    Action::PreparatoryTouch,   // 下地加工
+    Action::TrainedFinesse,     // 匠の絶技
+    Action::RefinedTouch,       // 洗練加工
+    Action::ByregotsBlessing,   // ビエルゴの祝福
+    Action::MastersMend,        // マスターズメンド
+    Action::ImmaculateMend,     // パーフェクトメンド
+    Action::Observe,            // 経過観察
+    Action::WasteNot,           // 倹約
+    Action::WasteNot2,          // 長期倹約
+    Action::GreatStrides,       // グレートストライド
+    Action::Innovation,         // イノベーション
+    Action::QuickInnovation,    // クイックイノベーション
+    Action::Manipulation,       // マニピュレーション
+    Action::TrainedPerfection,  // 匠の神業
+    Action::PreciseTouch,       // 集中加工
+    Action::TricksOfTheTrade,   // 秘訣
+    Action::HastyTouch,         // ヘイスティタッチ
+    Action::DaringTouch,        // デアリングタッチ
+    Action::BasicSynthesis,     // 作業
+    Action::CarefulSynthesis,   // 模範作業
+    Action::RapidSynthesis,     // 突貫作業
+    Action::IntensiveSynthesis, // 集中作業
+    Action::Groundwork,         // 下地作業
+    Action::DelicateSynthesis,  // 精密作業
+    Action::PrudentSynthesis,   // 倹約作業
+    Action::Veneration,         // ヴェネレーション
+    Action::CarefulObservation, // 設計変更
+    Action::Reflect,            // 確信
+    Action::MuscleMemory,       // 確信作業
+    Action::TrainedEye,         // 匠の早業
+    Action::FinalAppraisal,     // 最終確認
+    Action::HeartAndSoul,       // 一心不乱
+];
+
+impl Patch7 {
+    // 洗練加工は加工からのコンボの時にスタックが1つ多く溜まります
+    fn run_refined_touch(&self, s:&State, setting:&Setting) -> (State,u32) {
+        let stacks = if s.buff.basic_touch > 0 { 2 } else { 1 };
+        self.run_touch(s, setting, &Action::RefinedTouch, stacks, 1.0)
+    }
+
+    fn run_immaculate_mend(&self, s:&State, setting:&Setting) -> (State,u32) {
+        (self.consume(s,&Action::ImmaculateMend).add_durability(setting.max_durability,setting).next_turn(setting).set_immaculate_mend_used(),0)
+    }
+
+    // クイックイノベーションは一心不乱と同じく工程を進めないので、バフの残り工程も状態も変わりません
+    fn run_quick_innovation(&self, s:&State) -> (State,u32) {
+        (self.consume(s,&Action::QuickInnovation).set_innovation(1).set_quick_innovation_used(),0)
+    }
+
+    fn run_trained_perfection(&self, s:&State, setting:&Setting) -> (State,u32) {
+        (self.consume(s,&Action::TrainedPerfection).next_turn(setting).set_trained_perfection(),0)
+    }
+
+    // 倹約の効果が無い時に消費する耐久です
+    fn base_durability(&self, a:&Action) -> u8 {
+        match a {
+            Action::RefinedTouch => 10,
+            Action::DaringTouch => 10,
+            _ => Patch6::base_durability(a),
+        }
+    }
+}
+
+// 7.0で効果が変わったアクションです
+impl Actions for Patch7 {
+    // ヘイスティタッチに成功すると次のアクションでデアリングタッチが使えます
+    fn run_hasty_touch(&self, s:&State, setting:&Setting) -> (State,u32) {
+        let (ns,q) = self.run_touch(s, setting, &Action::HastyTouch, 1, 1.0);
+        (ns.set_expedience(), q)
+    }
+
+    fn delicate_synthesis_efficiency(&self, setting:&Setting) -> u32 {
+        trait_efficiency(setting, 94, 100, 150)
+    }
+
+    // 7.0で確信の効率は300%になりました
+    fn run_reflect(&self, s:&State, setting:&Setting) -> (State,u32) {
+        self.run_touch(s, setting, &Action::Reflect, 2, 3.0)
+    }
+}
+
+impl Patch6Actions for Patch7 {}
+
+impl CraftRules for Patch7 {
+    fn name(&self) -> &'static str {
+        "7.x"
+    }
+
+    fn candidate_actions(&self) -> &'static [Action] {
+        &CANDIDATE_ACTIONS
+    }
+
+    fn max_inner_quiet(&self) -> u8 {
+        Patch6.max_inner_quiet()
+    }
+
+    fn max_level(&self) -> u8 {
+        100
+    }
+
+    // 7.0で上級加工は経過観察からもコンボになりました
+    fn required_cp(&self, s:&State, a:&Action) -> u16 {
+        match a {
+            Action::AdvancedTouch => if s.buff.standard_touch > 0 || s.buff.observe > 0 { 18 } else { 46 },
+            Action::RefinedTouch => 24,
+            Action::ImmaculateMend => 112,
+            Action::QuickInnovation => 0,
+            Action::TrainedPerfection => 0,
+            Action::DaringTouch => 0,
+            _ => Patch6.required_cp(s, a),
+        }
+    }
+
+    // 匠の神業の効果中は耐久を消費しません
+    fn required_durability(&self, s:&State, a:&Action) -> u8 {
+        let d = self.base_durability(a);
+
+        if s.buff.trained_perfection > 0 { 0 } else if s.buff.waste_not > 0 { d / 2 } else { d }
+    }
+
+    // 匠の神業の効果は耐久を消費するアクションを使うと消えます
+    fn consume(&self, s:&State, a:&Action) -> State {
+        let ns = s.consume_cp(self.required_cp(s, a)).consume_durability(self.required_durability(s, a));
+
+        if self.base_durability(a) > 0 { ns.clear_trained_perfection() } else { ns }
+    }
+
+    fn usable(&self, s:&State, setting:&Setting, a:&Action) -> bool {
+        match a {
+            Action::RefinedTouch => true,
+            Action::DaringTouch => s.buff.expedience > 0,        // デアリングタッチはヘイスティタッチの成功直後のみ使えます
+            Action::QuickInnovation => s.buff.innovation == 0 && s.buff.quick_innovation_used == 0, // クイックイノベーションはイノベーションが無効の時に1回の製作で1度だけ使えます
+            Action::TrainedPerfection => s.buff.trained_perfection_used == 0, // 匠の神業は1回の製作で1度だけ使えます
+            Action::ImmaculateMend => s.buff.immaculate_mend_used == 0,       // パーフェクトメンドは1回の製作で1度だけ使えます
+            Action::FocusedTouch => false, // 7.0で削除されたアクションです
+            _ => Patch6.usable(s, setting, a),
+        }
+    }
+
+    fn success_rate(&self, _s:&State, a:&Action) -> u32 {
+        match a {
+            Action::HastyTouch => 60,
+            Action::DaringTouch => 60,
+            Action::RapidSynthesis => 50,
+            _ => 100,
+        }
+    }
+
+    fn run_succeeded_action(&self, s:&State, setting:&Setting, a:&Action) -> (State,u32) {
+        match a {
+            Action::RefinedTouch => self.run_refined_touch(s, setting),
+            Action::ImmaculateMend => self.run_immaculate_mend(s, setting),
+            Action::QuickInnovation => self.run_quick_innovation(s),
+            Action::TrainedPerfection => self.run_trained_perfection(s, setting),
+            Action::DaringTouch => self.run_touch(s, setting, a, 1, 1.5),
+            Action::FocusedTouch => panic!("Not available in 7.x"),
+            _ => self.run_patch6_action(s, setting, a),
+        }
+    }
+
+    fn keeps_condition(&self, a:&Action) -> bool {
+        *a == Action::QuickInnovation || Patch6.keeps_condition(a)
+    }
+
+    fn restores_cp(&self, a:&Action) -> bool {
+        Patch6.restores_cp(a)
+    }
+
+    fn max_cp_restore(&self) -> u16 {
+        Patch6.max_cp_restore()
+    }
+}
@@ -1,111 +1,136 @@
 
 use std::collections::HashSet;
-use super::logic::{State,Buff};
+use core::cmp::max;
+use super::logic::{State,Buff,Condition,Setting};
+
+// 表とインデックスの変換表に使うメモリの上限です。これを超える設定は計算を始める前にエラーにします
+pub const MAX_MEMORY : usize = 8 << 30;
 
 #[derive(Debug,Clone)]
 pub struct StateSpace {
     pub max_durability : u8,        // 最大耐久
     pub max_cp : u16,               // 最大CP
+    pub progress : u16,             // 完成に必要な工数(単位数)。0の場合は工数を追跡しません
     pub buffs: Vec<Buff>,           // 取りうるバフ一覧
-    buff_to_index: Vec<Option<usize>>,       // バフからインデックスへの変換
+    pub conditions: Vec<Condition>, // 取りうる状態一覧
+    radices: [usize;Buff::NUM_FIELDS],       // バフの各フィールドの基数
+    buff_to_index: Vec<Option<u32>>,         // バフからインデックスへの変換
 }
 
-fn travarse_states( states:&mut HashSet<Buff>, s:Buff, sustain:bool ) {
-    if states.insert( s.clone() ) {
-        let ns = s.next_turn();
-        travarse_states( states, Buff{ manipulation:8, ..ns }, sustain );
-        travarse_states( states, Buff{ innovation:4, ..ns }, sustain );
-        travarse_states( states, Buff{ great_strides:3, ..ns }, sustain );
-        travarse_states( states, Buff{ waste_not:4, ..ns }, sustain );
-        travarse_states( states, Buff{ waste_not:8, ..ns }, sustain );
-        travarse_states( states, Buff{ observe:1, ..ns }, sustain );
-        travarse_states( states, Buff{ basic_touch:1, great_strides:0, ..ns }, sustain ); // 加工したときの状態です。グレストも同時に消えます
-        travarse_states( states, Buff{ inner_quiet:0, great_strides:0, ..ns }, sustain ); // ビエルゴした時の状態です。グレストも同時に消えます
-
-        if sustain {
-            travarse_states( states, Buff{ manipulation:10, ..ns }, sustain );
-            travarse_states( states, Buff{ innovation:6, ..ns }, sustain );
-            travarse_states( states, Buff{ great_strides:5, ..ns }, sustain );
-            travarse_states( states, Buff{ waste_not:6, ..ns }, sustain );
-            travarse_states( states, Buff{ waste_not:10, ..ns }, sustain );
+// 仕上げを始める時点のバフから、ルールのアクションを実行して到達できるバフを全て列挙します。
+// バフ以外の要素は使用条件の判定にだけ使うので、CPと耐久は最大として、全ての状態(コンディション)について試します。
+fn generate_buffs( setting:&Setting, conditions:&[Condition], initial:Vec<Buff> ) -> Vec<Buff> {
+    let rules = setting.rules();
+    let mut states = HashSet::new();
+    let mut stack = initial;
+
+    while let Some(buff) = stack.pop() {
+        if !states.insert( buff ) {
+            continue;
         }
 
-        travarse_states( states, ns, sustain );
+        for condition in conditions {
+            let s = State { durability:setting.max_durability, cp:setting.max_cp, progress:0, condition:*condition, buff };
+            for a in rules.candidate_actions() {
+                if s.check_action(setting, a) {
+                    for (_,ns,_) in s.run_action(setting, a).iter() {
+                        if !states.contains( &ns.buff ) {
+                            stack.push( ns.buff );
+                        }
+                    }
+                }
+            }
+        }
     }
+
+    states.into_iter().collect()
 }
 
-fn initial_buff_state() -> Buff {
-    Buff {
-        inner_quiet:11,
-        manipulation:0,
-        innovation:0,
-        great_strides:0,
-        waste_not:0,
-        basic_touch:0,
-        observe:0,
+// 各フィールドの基数です
+fn radices( buffs:&[Buff] ) -> [usize;Buff::NUM_FIELDS] {
+    let mut radices = [1;Buff::NUM_FIELDS];
+    for s in buffs {
+        for (n,v) in radices.iter_mut().zip(s.fields().iter()) {
+            *n = max( *n, *v as usize + 1 );
+        }
     }
+    radices
 }
 
-fn generate_buffs( sustain:bool ) -> Vec<Buff> {
+impl StateSpace {
 
-    let mut states = HashSet::new();
-    travarse_states( &mut states, initial_buff_state(), sustain );
-    states.into_iter().collect()
-}
+    // 思った以上に表の参照に時間がかかるのでハッシュマップを使わずに直接計算します。
+    // 各フィールドの基数は列挙したバフの最大値から決めます
+    fn buff_to_addr( &self, s:&Buff ) -> Option<usize> {
+        let mut x = 0;
+        for (v,n) in s.fields().iter().zip(self.radices.iter()) {
+            if *v as usize >= *n {
+                return None;
+            }
+            x = x * n + *v as usize;
+        }
+        Some(x)
+    }
 
-impl StateSpace {
+    // バフの組み合わせが多すぎてインデックスの変換表がメモリに収まらない設定はエラーにします
+    pub fn new( setting:&Setting ) -> Result<StateSpace,String> {
+        let buffs = generate_buffs( setting, &StateSpace::conditions(setting), setting.rules().initial_buffs(setting) );
+        let bytes = radices(&buffs).iter().try_fold(size_of::<Option<u32>>(), |x,n| x.checked_mul(*n)).unwrap_or(usize::MAX);
+        if bytes > MAX_MEMORY {
+            return Err(format!("too many buff combinations: {} buffs need {} MiB to index (limit {} MiB). Turn off specialist or lower the crafter level",
+                buffs.len(), bytes >> 20, MAX_MEMORY >> 20));
+        }
+        Ok(StateSpace::with_buffs( setting, buffs ))
+    }
 
-    const N_MANIPULATION : usize = 10;
-    const N_INNOVATION : usize = 6;
-    const N_GREAT_STRIDES : usize = 5;
-    const N_WASTE_NOT : usize = 10;
-    const N_BASIC_TOUCH : usize = 2;
-    const N_OBSERVE : usize = 2;
-    const N_INNER_QUIET : usize = 2;
+    // 指定したバフから到達できるバフだけの状態空間です。小さな表で確かめるために使います
+    #[cfg(test)]
+    pub fn reachable_from( setting:&Setting, initial:Vec<Buff> ) -> StateSpace {
+        let buffs = generate_buffs( setting, &StateSpace::conditions(setting), initial );
+        StateSpace::with_buffs( setting, buffs )
+    }
 
-    // 思った以上に表の参照に時間がかかるのでハッシュマップを使わずに直接計算します。
-    // 厳密にはsustainの有無でテーブルサイズは変えられますがそこまでメモリを消費しないのでシンプルに定義します
-    fn buff_to_addr( s:&Buff ) -> usize {
-        let mut x = s.manipulation as usize;
-        x *= StateSpace::N_INNOVATION;
-        x += s.innovation as usize;
-        x *= StateSpace::N_GREAT_STRIDES;
-        x += s.great_strides as usize;
-        x *= StateSpace::N_WASTE_NOT;
-        x += s.waste_not as usize;
-        x *= StateSpace::N_BASIC_TOUCH;
-        x += s.basic_touch as usize;
-        x *= StateSpace::N_OBSERVE;
-        x += s.observe as usize;
-        x *= StateSpace::N_INNER_QUIET;
-        x += s.inner_quiet as usize;
-        x
-    }
-
-    pub fn new( max_durability: u8, max_cp: u16, sustain: bool ) -> StateSpace {
-        let buffs = generate_buffs( sustain );
+    fn conditions( setting:&Setting ) -> Vec<Condition> {
+        if setting.conditions { Condition::ALL.to_vec() } else { vec![Condition::Normal] }
+    }
 
-        let mut buff_to_index = Vec::new();
-        buff_to_index.resize( StateSpace::N_MANIPULATION * StateSpace::N_INNOVATION * StateSpace::N_GREAT_STRIDES * StateSpace::N_WASTE_NOT * StateSpace::N_BASIC_TOUCH * StateSpace::N_OBSERVE * StateSpace::N_INNER_QUIET, None );
+    // 列挙済みのバフから状態空間を作ります。バフの並びがそのまま表の並びになるので、
+    // 保存した表を読み込む時は保存時の並びを渡します
+    pub fn with_buffs( setting:&Setting, buffs:Vec<Buff> ) -> StateSpace {
+        let conditions = StateSpace::conditions(setting);
+        let radices = radices(&buffs);
+
+        let mut space = StateSpace {
+            max_durability: setting.max_durability,
+            max_cp: setting.max_cp,
+            progress: setting.required_progress(),
+            buffs,
+            conditions,
+            radices,
+            buff_to_index: Vec::new(),
+        };
 
-        for i in 0..buffs.len() {
-            let s = buffs[i];
-            buff_to_index[StateSpace::buff_to_addr(&s)] = Some(i);
+        let mut buff_to_index = Vec::new();
+        buff_to_index.resize( radices.iter().product(), None );
+        for (i,s) in space.buffs.iter().enumerate() {
+            buff_to_index[space.buff_to_addr(s).unwrap()] = Some(i as u32);
         }
+        space.buff_to_index = buff_to_index;
 
-        StateSpace {
-            max_durability: max_durability,
-            max_cp: max_cp,
-            buffs: buffs,
-            buff_to_index: buff_to_index,
-        }
+        space
+    }
+
+    // 工数を追跡しない場合も工数0の1通りとして数えます
+    fn num_progress(&self) -> usize {
+        if self.progress == 0 { 1 } else { self.progress as usize }
     }
 
     pub fn size_cp(&self) -> usize {
         let num_durability = (self.max_durability / 5) as usize;
         let num_buff = self.buffs.len();
+        let num_condition = self.conditions.len();
 
-        num_durability * num_buff
+        num_durability * self.num_progress() * num_buff * num_condition
     }
 
     pub fn size(&self) -> usize {
@@ -114,40 +139,82 @@ impl StateSpace {
         num_cp * self.size_cp()
     }
 
-    pub fn get_index(&self, s:&State) -> Option<usize> {
-        if s.durability == 0 || s.durability % 5 != 0 || s.durability > self.max_durability {
-            None
-        }
-        else if s.cp > self.max_cp {
+    // 状態(コンディション)を除いた部分のインデックスです。状態は最も内側の次元なので、これにcondition_offsetを足すとインデックスになります。
+    pub fn get_base_index(&self, s:&State) -> Option<usize> {
+        if s.durability == 0 || !s.durability.is_multiple_of(5) || s.durability > self.max_durability || s.cp > self.max_cp || s.progress as usize >= self.num_progress() {
             None
         }
         else {
             let cp : usize = s.cp as usize;
             let durability = (s.durability / 5) as usize - 1;
+            let progress = s.progress as usize;
             let num_durability = (self.max_durability / 5) as usize;
             let num_buff = self.buffs.len();
+            let num_condition = self.conditions.len();
 
-            match self.buff_to_index[StateSpace::buff_to_addr(&s.buff)] {
-                Some(i) => Some((cp * num_durability + durability) * num_buff + i),
-                None => None,
-            }
+            self.buff_to_addr(&s.buff).and_then(|x| self.buff_to_index[x]).map(|i| (((cp * num_durability + durability) * self.num_progress() + progress) * num_buff + i as usize) * num_condition)
         }
     }
 
+    pub fn condition_offset(&self, c:&Condition) -> Option<usize> {
+        self.conditions.iter().position(|x| x == c)
+    }
+
+    pub fn get_index(&self, s:&State) -> Option<usize> {
+        Some(self.get_base_index(s)? + self.condition_offset(&s.condition)?)
+    }
+
+    pub fn is_completed(&self, s:&State) -> bool {
+        self.progress > 0 && s.progress >= self.progress
+    }
+
     pub fn contains(&self, s:&State) -> bool {
-        match self.get_index(s) {
-            Some(_) => true,
-            None => false,
-        }
+        self.get_index(s).is_some()
     }
 
     pub fn get_state_by_cp_index(&self, cp:u16, index:usize) -> State {
         assert!( index < self.size_cp() );
 
+        let num_condition = self.conditions.len();
+        let index_buff = index / num_condition;
+        let index_progress = index_buff / self.buffs.len();
+
         State {
-            cp:cp,
-            durability: (index / self.buffs.len()) as u8 * 5 + 5,
-            buff: self.buffs[index % self.buffs.len()],
+            cp,
+            durability: (index_progress / self.num_progress()) as u8 * 5 + 5,
+            progress: (index_progress % self.num_progress()) as u16,
+            condition: self.conditions[index % num_condition],
+            buff: self.buffs[index_buff % self.buffs.len()],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rules::Rules;
+
+    // 新しい設定を全て切った既定の設定(5.5、工数と状態とマイスターなし)では、最初の版と同じバフの次元に
+    // インナークワイエットのスタック数(0-11)を加えただけの状態空間になります。
+    // 最初の版はスタック数を0と11の2通りとして3020通りのバフでした
+    #[test]
+    fn default_setting_stays_close_to_baseline() {
+        let setting = Setting::example( Rules::Patch5, 657, 55 );
+        let space = StateSpace::new( &setting ).unwrap();
+        let baseline = ["inner_quiet", "manipulation", "innovation", "great_strides", "waste_not", "basic_touch", "observe"];
+        for (name,radix) in Buff::FIELD_NAMES.iter().zip(space.radices.iter()) {
+            assert!( baseline.contains(name) || *radix == 1, "{} {}", name, radix );
+        }
+        assert!( space.buffs.len() <= 3020 / 2 * 12 );
+        assert!( space.size() <= space.buffs.len() * 11 * 658 );
+
+        // 6.xでは7.xの1回限りのアクションの、マイスターでなければ使用回数の次元がありません
+        let setting = Setting { crafter_level:90, ..Setting::example( Rules::Patch6, 300, 40 ) };
+        let space = StateSpace::new( &setting ).unwrap();
+        let unused = ["trained_perfection", "trained_perfection_used", "quick_innovation_used", "immaculate_mend_used",
+            "careful_observation_used", "heart_and_soul", "heart_and_soul_used", "first_step"];
+        for (name,radix) in Buff::FIELD_NAMES.iter().zip(space.radices.iter()) {
+            assert!( !unused.contains(name) || *radix == 1, "{} {}", name, radix );
         }
     }
 }
@@ -2,64 +2,224 @@
 use indicatif::ProgressIterator;
 use rayon::prelude::*;
 use core::ops::{Index,IndexMut};
+use core::cmp::{min,Reverse};
+use std::collections::HashSet;
+use std::cell::Cell;
 
-use super::logic::{State,Action,Setting};
-use super::statespace::{StateSpace};
+use super::logic::{State,Buff,Action,Outcomes,Setting};
+use super::statespace::{StateSpace,MAX_MEMORY};
+use super::cache::MappedValues;
 
 #[derive(Debug)]
 pub struct Table<T>
 {
-    values : Vec<T>,
+    values : Values<T>,
     space : StateSpace,
 }
 
-impl<T: Clone> Index<State> for Table<T> {
+// 表の中身です。計算した表はVecで持ち、キャッシュから読み込んだ表はファイルをマップしたまま参照します
+#[derive(Debug)]
+enum Values<T> {
+    Owned(Vec<T>),
+    Mapped(MappedValues),
+}
+
+// マップしたファイルから直接参照できる値です
+pub trait MappedValue : Sized {
+    fn mapped(values:&MappedValues, index:usize) -> &Self;
+}
+
+impl MappedValue for u32 {
+    fn mapped(values:&MappedValues, index:usize) -> &u32 {
+        &values.values()[index]
+    }
+}
+
+impl MappedValue for u8 {
+    fn mapped(values:&MappedValues, index:usize) -> &u8 {
+        values.action_code(index)
+    }
+}
+
+impl<T: MappedValue> Index<State> for Table<T> {
     type Output = T;
 
     fn index(&self, s:State) -> &Self::Output {
-        self.values.index(self.space.get_index(&s).unwrap())
+        self.at(self.space.get_index(&s).unwrap())
     }
 }
 
-impl<T: Clone> IndexMut<State> for Table<T> {
+// マップした表は読み込み専用です
+impl<T: MappedValue> IndexMut<State> for Table<T> {
     fn index_mut(&mut self, s:State) -> &mut Self::Output {
-        self.values.index_mut(self.space.get_index(&s).unwrap())
+        let index = self.space.get_index(&s).unwrap();
+        match &mut self.values {
+            Values::Owned(v) => v.index_mut(index),
+            Values::Mapped(_) => panic!("Mapped table is read-only"),
+        }
+    }
+}
+
+impl<T: MappedValue> Table<T> {
+    // 状態空間での並び順の番号で参照します
+    pub fn at(&self, index:usize) -> &T {
+        match &self.values {
+            Values::Owned(v) => &v[index],
+            Values::Mapped(m) => T::mapped(m, index),
+        }
     }
 }
 
 impl<T> Table<T> {
+    pub fn new(values:Vec<T>, space:StateSpace) -> Table<T> {
+        Table { values:Values::Owned(values), space }
+    }
+
+    pub fn mapped(values:MappedValues, space:StateSpace) -> Table<T> {
+        Table { values:Values::Mapped(values), space }
+    }
+
+    pub fn space(&self) -> &StateSpace {
+        &self.space
+    }
+
     pub fn contains(&self, s:&State) -> bool {
         self.space.contains(s)
     }
+
+    pub fn is_completed(&self, s:&State) -> bool {
+        self.space.is_completed(s)
+    }
+}
+
+// 評価値の参照先です。表の計算では計算済みの領域を、遅延評価では参照元から見た時点の評価値を返します。
+// 参照できない(まだ計算していない)場合はNoneです。sは状態(コンディション)まで決まった状態で、indexは表の中の位置です
+pub trait ValueSource {
+    fn value(&self, s:&State, index:usize) -> Option<u32>;
 }
 
-fn calc_value( setting:&Setting, values:&[u32], space:&StateSpace, s:&State ) -> (Action,u32) {
+impl ValueSource for [u32] {
+    fn value(&self, _s:&State, index:usize) -> Option<u32> {
+        self.get(index).copied()
+    }
+}
+
+impl ValueSource for Table<u32> {
+    fn value(&self, _s:&State, index:usize) -> Option<u32> {
+        Some(*self.at(index))
+    }
+}
+
+// 手順を表示するための、状態ごとの最善のアクションです
+pub trait Policy {
+    fn contains(&self, s:&State) -> bool;
+    fn is_completed(&self, s:&State) -> bool;
+    fn action(&self, s:&State) -> Action;
+
+    // 仕上げを始めてから上げた品質も使って選ぶ場合のアクションです
+    fn action_at(&self, s:&State, _quality:u32) -> Action {
+        self.action(s)
+    }
+}
+
+// アクションの番号です。ルールの候補の何番目か(1から、0は選択肢なし)で、表にはActionではなくこの番号を1バイトずつ持ちます
+pub fn action_code( setting:&Setting, a:&Action ) -> u8 {
+    setting.rules().candidate_actions().iter().position(|x| x == a).map_or(0, |i| i as u8 + 1)
+}
+
+// 状態ごとの最善のアクションの表です。アクションは番号で持ち、参照する時に実際のアクションに戻します
+#[derive(Debug)]
+pub struct ActionTable {
+    codes : Table<u8>,
+    actions : Vec<Action>,  // アクションの番号から実際のアクションへの変換
+}
+
+impl ActionTable {
+    pub fn new( setting:&Setting, codes:Table<u8> ) -> ActionTable {
+        let mut actions = vec![Action::CannotAction];
+        actions.extend_from_slice(setting.rules().candidate_actions());
+        ActionTable { codes, actions }
+    }
+
+    pub fn code_at(&self, index:usize) -> u8 {
+        *self.codes.at(index)
+    }
+
+    // 範囲外の番号は選択肢なしとして扱います(壊れたキャッシュはチェックサムで読み込みませんが、念のため)
+    fn decode(&self, code:u8) -> &Action {
+        self.actions.get(code as usize).unwrap_or(&Action::CannotAction)
+    }
+}
+
+impl Index<State> for ActionTable {
+    type Output = Action;
+
+    fn index(&self, s:State) -> &Self::Output {
+        self.decode(self.codes[s])
+    }
+}
+
+impl Policy for ActionTable {
+    fn contains(&self, s:&State) -> bool {
+        self.codes.contains(s)
+    }
+
+    fn is_completed(&self, s:&State) -> bool {
+        self.codes.is_completed(s)
+    }
+
+    fn action(&self, s:&State) -> Action {
+        self[*s]
+    }
+}
+
+// 工数を追跡する場合に完成させられない状態や、残すCPと耐久を守れない状態の評価値です
+pub const INFEASIBLE : u32 = u32::MAX;
+
+// 行動後の状態について、アクションで上がった品質と次の工程の状態(コンディション)の期待値を計算します。
+// 遷移先が状態空間外であったり、まだ計算していない領域、完成させられない状態の場合はNoneを返します。
+// 完成した状態はそこで製作が終わるので、上がった品質だけが評価値になります。
+// 表は品質の上限のない設定(Setting::table_setting)で計算します。上限がある場合は遅延評価が答えます
+fn expected_value<V: ValueSource + ?Sized>( setting:&Setting, values:&V, space:&StateSpace, s:&State, a:&Action, ns:&State, q:u32 ) -> Option<u32> {
+    if space.is_completed(ns) {
+        return Some(q);
+    }
+
+    let base = space.get_base_index(ns)?;
+    let mut sum : u64 = 0;
+
+    for (c,p) in s.condition.next(setting, a) {
+        let index = base + space.condition_offset(c)?;
+        match values.value(&State { condition:*c, ..*ns }, index)? {
+            INFEASIBLE => return None,
+            v => sum += *p as u64 * (q as u64 + v as u64),
+        }
+    }
+
+    Some((sum / 100) as u32)
+}
+
+// アクションの成否について期待値を計算します。
+fn expected_outcome_value<V: ValueSource + ?Sized>( setting:&Setting, values:&V, space:&StateSpace, s:&State, a:&Action, outcomes:&Outcomes ) -> Option<u32> {
+    let mut sum : u64 = 0;
+
+    for (p,ns,q) in outcomes.iter() {
+        sum += p as u64 * expected_value( setting, values, space, s, a, &ns, q )? as u64;
+    }
+
+    Some((sum / 100) as u32)
+}
+
+pub fn calc_value<V: ValueSource + ?Sized>( setting:&Setting, values:&V, space:&StateSpace, s:&State ) -> (Action,u32) {
+    // 工数を追跡する場合や、残すCPと耐久が足りない場合は、何もせずに終わると失敗になります
     let mut max_a = Action::CannotAction;
-    let mut max_v = 0;
-
-    const CANDIDATE_ACTIONS : [Action;13] = [
-        Action::BasicTouch,         // 加工
-        Action::StandardTouch,      // 中級加工
-        Action::PrudentTouch,       // 倹約加工
-        Action::FocusedTouch,       // 注視加工
-        Action::PreparatoryTouch,   // 下地加工
-        Action::ByregotsBlessing,   // ビエルゴの祝福
-        Action::MastersMend,        // マスターズメンド
-        Action::Observe,            // 経過観察
-        Action::WasteNot,           // 倹約
-        Action::WasteNot2,          // 長期倹約
-        Action::GreatStrides,       // グレートストライド
-        Action::Innovation,         // イノベーション
-        Action::Manipulation,       // マニピュレーション
-    ];
-
-    for a in CANDIDATE_ACTIONS {
-        if s.check_action(&a) {
-            let (ns,q) = s.run_action( setting, &a );
-            if let Some(index) = space.get_index(&ns) {
-                let v = q + values[index];
-                if v > max_v {
-                    max_a = a;
+    let mut max_v = if space.progress > 0 || !setting.can_finish(s) { INFEASIBLE } else { 0 };
+
+    for a in setting.rules().candidate_actions() {
+        if s.check_action(setting, a) {
+            if let Some(v) = expected_outcome_value( setting, values, space, s, a, &s.run_action( setting, a ) ) {
+                if max_v == INFEASIBLE || v > max_v {
+                    max_a = *a;
                     max_v = v;
                 }
             }
@@ -69,38 +229,418 @@ fn calc_value( setting:&Setting, values:&[u32], space:&StateSpace, s:&State ) ->
     (max_a,max_v)
 }
 
-pub fn calc_table( setting:&Setting ) -> (Table<u32>,Table<Action>) {
+// 状態で使えるアクションを、その後は表の最善の手順を続けた場合の評価値の高い順に並べて、上位k個を返します。
+// 表には最善のアクションしか持たないので、遷移先の評価値から1手分だけ計算します
+pub fn best_actions( setting:&Setting, values:&Table<u32>, s:&State, k:usize ) -> Vec<(Action,u32)> {
+    let mut actions : Vec<(Action,u32)> = setting.rules().candidate_actions().iter().filter(|a| s.check_action(setting, a)).filter_map(|a| {
+        let v = expected_outcome_value( setting, values, values.space(), s, a, &s.run_action( setting, a ) )?;
+        Some((*a,v))
+    }).collect();
 
-    let space = StateSpace::new(setting.max_durability, setting.max_cp, setting.sustain);
+    actions.sort_by_key(|(_,v)| Reverse(*v));
+    actions.truncate(k);
+    actions
+}
+
+// 秘訣のようにCPを回復するアクションを使える状態があるかどうかです。
+// バフと状態(コンディション)の組み合わせごとに、CPと耐久を最大として使用条件を調べます
+pub fn can_restore_cp( setting:&Setting, space:&StateSpace ) -> bool {
+    let rules = setting.rules();
+    let restores : Vec<&Action> = rules.candidate_actions().iter().filter(|a| rules.restores_cp(a)).collect();
+    !restores.is_empty() && space.buffs.iter().any(|buff| space.conditions.iter().any(|condition| {
+        let s = State { cp:setting.max_cp, durability:setting.max_durability, progress:0, condition:*condition, buff:*buff };
+        restores.iter().any(|a| s.check_action(setting, a))
+    }))
+}
+
+// 全ての領域を参照して、CPがcpの領域をlayer_orderの順に計算し直します。評価値が変わった状態があればtrueを返します
+fn recompute_layer( setting:&Setting, space:&StateSpace, v_buffer:&mut [u32], a_buffer:&mut [u8], groups:&[&[u32]], cp:u16 ) -> bool {
+    let begin = space.size_cp() * cp as usize;
+    let mut changed = false;
+
+    for group in groups {
+        let computed = &*v_buffer;
+        let updates : Vec<(usize,Action,u32)> = group.par_iter().filter_map(|index| {
+            let s = space.get_state_by_cp_index( cp, *index as usize );
+            let index = begin + *index as usize;
+            let (a,v) = calc_value( setting, computed, space, &s );
+            if v != computed[index] || action_code( setting, &a ) != a_buffer[index] { Some((index,a,v)) } else { None }
+        }).collect();
 
+        for (index,a,v) in updates {
+            changed |= v_buffer[index] != v;
+            v_buffer[index] = v;
+            a_buffer[index] = action_code( setting, &a );
+        }
+    }
+    changed
+}
+
+// 評価値とアクションの表がメモリの上限を超える設定はエラーにします
+fn check_table_size( space:&StateSpace ) -> Result<(),String> {
+    let bytes = space.size().saturating_mul( size_of::<u32>() + size_of::<u8>() );
+    if bytes > MAX_MEMORY {
+        Err(format!("table too large: {} states need {} MiB (limit {} MiB). Lower max CP or durability, turn conditions off or use lazy",
+            space.size(), bytes >> 20, MAX_MEMORY >> 20))
+    }
+    else {
+        Ok(())
+    }
+}
+
+pub fn calc_table( setting:&Setting ) -> Result<(Table<u32>,ActionTable),String> {
+    let space = StateSpace::new(setting)?;
+    check_table_size( &space )?;
+    Ok(calc_table_in( setting, space ))
+}
+
+fn calc_table_in( setting:&Setting, space:StateSpace ) -> (Table<u32>,ActionTable) {
     let mut v_buffer = Vec::new();
     let mut a_buffer = Vec::new();
     v_buffer.resize( space.size(), 0 );
-    a_buffer.resize( space.size(), Action::CannotAction );
+    a_buffer.resize( space.size(), 0 );
+
+    fill_table( setting, &space, &mut v_buffer, &mut a_buffer, 0 );
+
+    (Table::new( v_buffer, space.clone() ),
+     ActionTable::new( setting, Table::new( a_buffer, space ) ))
+}
+
+// 既存の表を高い最大CPまで広げます。各領域は低いCPの領域だけを参照するので、増えたCPの領域だけを計算します。
+// 秘訣のようにCPを回復するアクションを使える場合は、低いCPの領域も増えたCPの領域を参照して評価値が変わるので最初から計算します。
+// 最大CP以外の設定は同じものとします。元の最大CPでは使えなかったアクションがあってバフの一覧が変わる場合も最初から計算します
+pub fn extend_table( setting:&Setting, values:&Table<u32>, actions:&ActionTable ) -> Result<(Table<u32>,ActionTable),String> {
+    let old = values.space();
+    let space = StateSpace::new(setting)?;
+    let old_buffs : HashSet<&Buff> = old.buffs.iter().collect();
+    if old.max_cp > setting.max_cp || old.max_durability != space.max_durability || old.progress != space.progress || old.conditions != space.conditions ||
+        old.buffs.len() != space.buffs.len() || !space.buffs.iter().all(|b| old_buffs.contains(b)) || can_restore_cp( setting, &space ) {
+        return calc_table( setting );
+    }
+
+    // 並び順を合わせるために元の表のバフの一覧を使います
+    let space = StateSpace::with_buffs( setting, old.buffs.clone() );
+    check_table_size( &space )?;
+    let begin_cp = old.max_cp + 1;
+    let computed = space.size_cp() * begin_cp as usize;
+
+    let mut v_buffer : Vec<u32> = (0..computed).map(|i| *values.at(i)).collect();
+    let mut a_buffer : Vec<u8> = (0..computed).map(|i| actions.code_at(i)).collect();
+    v_buffer.resize( space.size(), 0 );
+    a_buffer.resize( space.size(), 0 );
+
+    fill_table( setting, &space, &mut v_buffer, &mut a_buffer, begin_cp );
+
+    Ok((Table::new( v_buffer, space.clone() ),
+        ActionTable::new( setting, Table::new( a_buffer, space ) )))
+}
+
+// 同じCPの領域を計算している間の各状態の計算の進み具合です
+#[derive(Clone,Copy,PartialEq,Eq)]
+enum Progress {
+    Uncomputed, // まだ計算していません
+    Pending,    // 計算しましたが、未確定の状態を参照しました
+    Fixed,      // 確定しました
+}
+
+// 同じCPの領域を計算する時の参照先です。CPの低い領域は確定済みとして、同じCPの領域は進み具合を見て参照します。
+// 未計算や未確定の状態を参照した場合は、参照元も未確定として覚えておきます
+struct LayerValues<'a> {
+    values : &'a [u32],
+    begin : usize,
+    progress : &'a [Progress],
+    pending : Cell<bool>,
+}
+
+impl ValueSource for LayerValues<'_> {
+    fn value(&self, _s:&State, index:usize) -> Option<u32> {
+        if index < self.begin {
+            return self.values.get(index).copied();
+        }
+        match self.progress.get(index - self.begin)? {
+            Progress::Uncomputed => {
+                self.pending.set(true);
+                None
+            },
+            Progress::Pending => {
+                self.pending.set(true);
+                Some(self.values[index])
+            },
+            Progress::Fixed => Some(self.values[index]),
+        }
+    }
+}
+
+// 同じCPの領域を計算する順番です。CPを消費しないアクションの遷移先が先になるように並べます。
+// 1回の製作で1度だけ使えるアクションは使用済みの数が増えます。それ以外は「耐久 + マニピュレーションで回復する耐久」が
+// 消費した耐久の分だけ減り、消費しない場合でも匠の神業の効果が消えるか工数が増えます
+fn layer_order( s:&State ) -> (Reverse<u8>,u16,u8,Reverse<u16>) {
+    (Reverse(s.buff.num_used()), s.durability as u16 + 5 * s.buff.manipulation as u16, s.buff.trained_perfection, Reverse(s.progress))
+}
 
-    // CannotActionを除いて全てCPを消費するアクションですから、CP順に処理すれば参照先が未計算ということはないです。
+// begin_cpから最大CPまでの領域を計算します。begin_cpより下の領域は計算済みとします
+fn fill_table( setting:&Setting, space:&StateSpace, v_buffer:&mut [u32], a_buffer:&mut [u8], begin_cp:u16 ) {
+    // CannotActionと秘訣を除いてほとんどのアクションはCPを消費するので、CP順に処理すれば参照先は計算済みです。
     // CannotActionの場合はどこを参照することもなく単に評価値が0になります。
-    for cp in (0..=setting.max_cp).progress() {
-
-        // 計算領域を計算済み領域(v1とa1)と未計算領域(v2とa2)に分割します。
-        // 計算済み領域はcp=0で始まる領域で、未計算領域は現イテレーションのcpで始まる領域です。
-        // 更に現在の現イテレーションの領域に限定したスライスをvc、ac、と定義します。
-        let (v1,v2) = v_buffer.split_at_mut( space.size_cp() * cp as usize );
-        let ( _,a2) = a_buffer.split_at_mut( space.size_cp() * cp as usize );
-        let vc = &mut v2[0..space.size_cp()];
-        let ac = &mut a2[0..space.size_cp()];
-
-        // 現イテレーションの全状態について計算します。
-        vc.par_iter_mut().zip(ac.par_iter_mut()).enumerate().chunks(1024).for_each(|slice| {
-            for (index,(pv,pa)) in slice {
+    // CPを消費しないアクションは遷移先が同じCPの領域になるので、同じCPの領域はlayer_orderの順にまとめて計算します。
+    // それでも未計算の状態を参照した状態(データ定義のアクションなど)は未確定として、
+    // 同じCPの領域を全て計算した後で評価値が変わらなくなるまで計算し直します。
+    // バフの残り工程は必ず減るので同じCPの中で遷移が循環することはなく、評価値は一通りに決まります。
+    let key = |index:&u32| layer_order( &space.get_state_by_cp_index( 0, *index as usize ) );
+    let mut order : Vec<u32> = (0..space.size_cp() as u32).collect();
+    order.sort_by_key(key);
+
+    for cp in (begin_cp..=setting.max_cp).progress() {
+        let begin = space.size_cp() * cp as usize;
+        let end = begin + space.size_cp();
+        let mut progress = vec![Progress::Uncomputed; space.size_cp()];
+
+        for group in order.chunk_by(|x,y| key(x) == key(y)) {
+            // 計算済み領域と同じCPの計算済みの状態を参照して、同じ順番の状態をまとめて計算します。
+            let updates : Vec<(usize,Action,u32,bool)> = group.par_iter().map(|index| {
+                let index = *index as usize;
                 let s = space.get_state_by_cp_index( cp, index );
-                let (a,v) = calc_value( &setting, &v1, &space, &s );
-                *pv = v;
-                *pa = a;
+                let values = LayerValues { values:&v_buffer[0..end], begin, progress:&progress, pending:Cell::new(false) };
+                let (a,v) = calc_value( setting, &values, space, &s );
+                (index,a,v,values.pending.get())
+            }).collect();
+
+            for (index,a,v,pending) in updates {
+                v_buffer[begin + index] = v;
+                a_buffer[begin + index] = action_code( setting, &a );
+                progress[index] = if pending { Progress::Pending } else { Progress::Fixed };
+            }
+        }
+
+        // 未確定の状態を、評価値が変わらなくなるまで計算し直します。
+        let pending : Vec<usize> = (0..space.size_cp()).filter(|i| progress[*i] == Progress::Pending).collect();
+        loop {
+            let computed = &v_buffer[0..end];
+            let updates : Vec<(usize,Action,u32)> = pending.par_iter().filter_map(|index| {
+                let s = space.get_state_by_cp_index( cp, *index );
+                let (a,v) = calc_value( setting, computed, space, &s );
+                if v != computed[begin + index] || action_code( setting, &a ) != a_buffer[begin + index] { Some((begin + index,a,v)) } else { None }
+            }).collect();
+
+            if updates.is_empty() {
+                break;
+            }
+
+            for (index,a,v) in updates {
+                v_buffer[index] = v;
+                a_buffer[index] = action_code( setting, &a );
+            }
+        }
+    }
+
+    // CPを回復するアクションの遷移先はCPの高い領域なので、CPの低い順の計算では選ばれません。
+    // その場合は全ての領域をCPの低い順に、評価値が変わらなくなるまで計算し直します(価値反復)。
+    // 最初の計算は秘訣を使わない場合の評価値なので、計算し直すたびに評価値は増えていき、最後は秘訣を含めた正確な評価値になります。
+    // 各領域はCPが最大回復量の分だけ高い領域までしか参照しないので、その範囲の評価値が前に計算した後で変わった領域だけを計算し直します
+    if can_restore_cp( setting, space ) {
+        let max_cp_restore = setting.rules().max_cp_restore();
+        let groups : Vec<&[u32]> = order.chunk_by(|x,y| key(x) == key(y)).collect();
+        let num_cp = setting.max_cp as usize + 1;
+        let mut computed_at = vec![0;num_cp]; // 領域を最後に計算し直した時刻
+        let mut changed_at = vec![0;num_cp];  // 領域の評価値が最後に変わった時刻
+        let mut clock = 1;
+        loop {
+            let mut changed = false;
+            for cp in (0..num_cp).progress() {
+                let end = min(cp + max_cp_restore as usize, num_cp - 1);
+                if changed_at[0..=end].iter().all(|t| *t < computed_at[cp]) {
+                    continue;
+                }
+                computed_at[cp] = clock;
+                if recompute_layer( setting, space, v_buffer, a_buffer, &groups, cp as u16 ) {
+                    changed_at[cp] = clock;
+                    changed = true;
+                }
+                clock += 1;
             }
-        });
+            if !changed {
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logic::Condition;
+    use crate::rules::Rules;
+    use std::collections::HashMap;
+
+    // 倹約とマニピュレーションがあるとヘイスティタッチは耐久もCPも減らさず、遷移先が同じ領域になります
+    #[test]
+    fn hasty_touch_without_net_durability_loss() {
+        let setting = Setting::example( Rules::Patch5, 0, 20 );
+        let buffs = [(0,0),(4,0),(0,8),(4,8)].map(|(waste_not,manipulation)| Buff { inner_quiet:5, waste_not, manipulation, ..Default::default() });
+        let (tv,ta) = calc_table_in( &setting, StateSpace::reachable_from( &setting, buffs.to_vec() ) );
+        for buff in buffs {
+            let s = State::example( 0, 20, buff );
+            assert_eq!( ta[s], Action::HastyTouch );
+        }
+
+        // 倹約もマニピュレーションもある方が、どちらか片方だけより品質は上がります
+        let values = buffs.map(|buff| tv[State::example( 0, 20, buff )]);
+        assert!( values[3] > values[1] && values[3] > values[2] );
+    }
+
+    // マニピュレーションの効果中に匠の神業を使うと、同じCPの耐久の高い状態に遷移します。
+    // その後のヘイスティタッチも耐久を消費しないので同じCPに留まります
+    #[test]
+    fn trained_perfection_under_manipulation() {
+        let setting = Setting { crafter_level:100, ..Setting::example( Rules::Patch7, 0, 20 ) };
+        let buff = Buff { inner_quiet:10, manipulation:1, ..Default::default() };
+        let (_,ta) = calc_table_in( &setting, StateSpace::reachable_from( &setting, vec![buff] ) );
+        assert_eq!( ta[State::example( 0, 10, buff )], Action::TrainedPerfection );
+    }
+
+    // クイックイノベーションは工程を進めないので、マニピュレーションの回復もバフの残り工程の減少も起きません。
+    // 遷移先は同じCPの同じ耐久の状態ですが、使用済みの数が増えるので先に計算されています
+    #[test]
+    fn quick_innovation_keeps_step() {
+        let setting = Setting { crafter_level:100, specialist:true, ..Setting::example( Rules::Patch7, 0, 20 ) };
+        let buff = Buff { inner_quiet:10, manipulation:1, trained_perfection_used:1, heart_and_soul_used:1, ..Default::default() };
+        let s = State::example( 0, 15, buff );
+        let (ns,_) = s.run_action( &setting, &Action::QuickInnovation ).success;
+        assert_eq!( (ns.durability, ns.buff.manipulation, ns.buff.innovation), (15, 1, 1) );
+        assert!( setting.rules().keeps_condition( &Action::QuickInnovation ) );
+
+        let (_,ta) = calc_table_in( &setting, StateSpace::reachable_from( &setting, vec![buff] ) );
+        assert_eq!( ta[s], Action::QuickInnovation );
+    }
+
+    // 仕上げの後の作業に残すCPと耐久を下回るアクションは使いません。最初から足りない状態では終われません
+    #[test]
+    fn reserve_for_finishing_synthesis() {
+        let buff = Buff { inner_quiet:11, ..Default::default() };
+        let setting = Setting { reserve_cp:18, reserve_durability:15, ..Setting::example( Rules::Patch5, 18, 20 ) };
+        assert!( setting.can_finish( &State::example( 18, 15, buff ) ) );
+        assert!( !setting.can_finish( &State::example( 18, 10, buff ) ) && !setting.can_finish( &State::example( 17, 20, buff ) ) );
+
+        let (tv,ta) = calc_table_in( &setting, StateSpace::reachable_from( &setting, vec![buff] ) );
+        assert_eq!( ta[State::example( 18, 20, buff )], Action::CannotAction );
+        assert_eq!( tv[State::example( 18, 10, buff )], INFEASIBLE );
+
+        // 耐久だけを残す場合は、CPを使わないヘイスティタッチも使えます
+        let setting = Setting { reserve_durability:10, ..setting };
+        let (_,ta) = calc_table_in( &setting, StateSpace::reachable_from( &setting, vec![buff] ) );
+        assert_eq!( ta[State::example( 18, 20, buff )], Action::HastyTouch );
+    }
+
+    // 工数を追跡する場合は、完成した状態だけが成功です。
+    // CPが無く耐久が1工程分だけの場合、作業で完成に届けばその作業を使い、届かなければどのアクションでも失敗します
+    #[test]
+    fn progress_completes_or_fails() {
+        let setting = Setting { crafter_level:90, difficulty:3000, ..Setting::example( Rules::Patch6, 0, 10 ) };
+        let required = setting.required_progress();
+        let (tv,ta) = calc_table_in( &setting, StateSpace::reachable_from( &setting, vec![Buff::default()] ) );
+
+        let step = State::example( 0, 10, Buff::default() ).run_action( &setting, &Action::BasicSynthesis ).success.0.progress;
+        let s = State { progress:required - step, ..State::example( 0, 10, Buff::default() ) };
+        let (ns,_) = s.run_action( &setting, &Action::BasicSynthesis ).success;
+        assert!( tv.is_completed( &ns ) );
+        assert_eq!( ta[s], Action::BasicSynthesis );
+        assert_ne!( tv[s], INFEASIBLE );
+
+        // 1単位足りない場合は、成功率50%で届く高速作業も失敗する分があるので使えません
+        let s = State { progress:required - step - 1, ..s };
+        assert!( tv.is_completed( &s.run_action( &setting, &Action::RapidSynthesis ).success.0 ) );
+        assert_eq!( (ta[s], tv[s]), (Action::CannotAction, INFEASIBLE) );
     }
 
-    (Table { values:v_buffer, space:space.clone() },
-     Table { values:a_buffer, space:space.clone() } )
+    // 最初のアクションの候補は評価値の高い順に並び、最も高い候補は表のアクションと評価値に一致します
+    #[test]
+    fn best_actions_in_order() {
+        let setting = Setting::example( Rules::Patch5, 40, 20 );
+        let (tv,ta) = calc_table( &setting ).unwrap();
+        let s = State::example( 40, 20, Buff { inner_quiet:11, ..Default::default() } );
+
+        let actions = best_actions( &setting, &tv, &s, 3 );
+        assert_eq!( actions.len(), 3 );
+        assert_eq!( actions[0], (ta[s], tv[s]) );
+        assert!( actions.windows(2).all(|w| w[0].1 >= w[1].1) );
+
+        let all = best_actions( &setting, &tv, &s, 100 );
+        assert_eq!( &all[..3], &actions[..] );
+        assert!( all.len() <= setting.rules().candidate_actions().iter().filter(|a| s.check_action( &setting, a )).count() );
+    }
+
+    // 広げた表は、秘訣を使えるかどうかに関わらず最初から計算した表と同じになります
+    #[test]
+    fn extended_table_equals_fresh_table() {
+        for conditions in [false,true] {
+            let setting = Setting { conditions, ..Setting::example( Rules::Patch5, 20, 10 ) };
+            let (tv,ta) = calc_table( &setting ).unwrap();
+            let setting = Setting { max_cp:30, ..setting };
+            assert_eq!( can_restore_cp( &setting, tv.space() ), conditions );
+
+            let (ev,ea) = extend_table( &setting, &tv, &ta ).unwrap();
+            let (fv,fa) = calc_table( &setting ).unwrap();
+            let space = fv.space();
+            for cp in 0..=setting.max_cp {
+                for index in 0..space.size_cp() {
+                    let s = space.get_state_by_cp_index( cp, index );
+                    assert_eq!( (ev[s],ea[s]), (fv[s],fa[s]) );
+                }
+            }
+        }
+    }
+
+    // 丸めのない実数で、到達できる状態の評価値を変わらなくなるまで計算します(秘訣の遷移を含む価値反復)
+    fn exact_values( setting:&Setting, space:&StateSpace, initial:&[State] ) -> HashMap<State,f64> {
+        let successors = |s:&State, a:&Action| s.run_action( setting, a ).iter().flat_map(|(p,ns,q)| {
+            s.condition.next( setting, a ).iter().map(move |(c,pc)| ((p * pc) as f64 / 10000.0, State { condition:*c, ..ns }, q as f64))
+        }).collect::<Vec<_>>();
+
+        let mut states = initial.to_vec();
+        let mut indices : HashMap<State,usize> = states.iter().enumerate().map(|(i,s)| (*s,i)).collect();
+        let mut transitions = vec![];
+        while transitions.len() < states.len() {
+            let s = states[transitions.len()];
+            let actions : Vec<Vec<(f64,Option<usize>,f64)>> = setting.rules().candidate_actions().iter().filter(|a| s.check_action( setting, a )).map(|a| {
+                successors( &s, a ).into_iter().map(|(p,ns,q)| {
+                    let index = space.contains(&ns).then(|| *indices.entry(ns).or_insert_with(|| { states.push(ns); states.len() - 1 }));
+                    (p,index,q)
+                }).collect()
+            }).collect();
+            transitions.push(actions);
+        }
+
+        // 遷移先が先になるように、見つけた順と逆に計算します
+        let mut values = vec![0.0;states.len()];
+        loop {
+            let mut delta : f64 = 0.0;
+            for i in (0..states.len()).rev() {
+                let v = transitions[i].iter().filter_map(|outcomes| {
+                    outcomes.iter().map(|(p,index,q)| index.map(|j| p * (q + values[j]))).sum::<Option<f64>>()
+                }).fold(0.0, f64::max);
+                delta = delta.max(v - values[i]);
+                values[i] = v;
+            }
+            if delta < 1e-9 {
+                return states.into_iter().zip(values).collect();
+            }
+        }
+    }
+
+    // 秘訣でCPが戻る遷移を含めても、表の評価値は丸めの誤差の範囲で正確な評価値に一致します。
+    // 表は期待値を取るたびに切り捨てるので、正確な評価値を超えることはなく、下回るのも数手分の切り捨ての範囲です
+    #[test]
+    fn tricks_of_the_trade_matches_exact_values() {
+        let setting = Setting { conditions:true, ..Setting::example( Rules::Patch5, 40, 15 ) };
+        let (tv,_) = calc_table( &setting ).unwrap();
+        let initial : Vec<State> = Condition::ALL.iter().flat_map(|condition| [1,6,11].map(|inner_quiet| {
+            State { condition:*condition, ..State::example( 40, 15, Buff { inner_quiet, ..Default::default() } ) }
+        })).collect();
+
+        let exact = exact_values( &setting, tv.space(), &initial );
+        for s in &initial {
+            let v = tv[*s] as f64;
+            assert!( v <= exact[s] + 1e-6 && exact[s] - v < 10.0, "{:?}: table {} exact {}", s, v, exact[s] );
+        }
+    }
 }